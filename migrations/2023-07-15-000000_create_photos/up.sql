@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS photos (
+    hash BIGINT PRIMARY KEY NOT NULL,
+    original_path TEXT NOT NULL,
+    current_path TEXT NOT NULL,
+    exif_json TEXT NOT NULL,
+    exif_date BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS duplicates (
+    id INTEGER PRIMARY KEY NOT NULL,
+    hash BIGINT NOT NULL,
+    original_path TEXT NOT NULL,
+    deleted BOOLEAN NOT NULL DEFAULT 0
+);
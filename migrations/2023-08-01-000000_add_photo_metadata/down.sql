@@ -0,0 +1,9 @@
+ALTER TABLE photos DROP COLUMN metadata_sources;
+ALTER TABLE photos DROP COLUMN focal_length;
+ALTER TABLE photos DROP COLUMN aperture;
+ALTER TABLE photos DROP COLUMN shutter;
+ALTER TABLE photos DROP COLUMN iso;
+ALTER TABLE photos DROP COLUMN serial;
+ALTER TABLE photos DROP COLUMN lens;
+ALTER TABLE photos DROP COLUMN camera_model;
+ALTER TABLE photos DROP COLUMN camera_make;
@@ -0,0 +1,9 @@
+ALTER TABLE photos ADD COLUMN camera_make TEXT;
+ALTER TABLE photos ADD COLUMN camera_model TEXT;
+ALTER TABLE photos ADD COLUMN lens TEXT;
+ALTER TABLE photos ADD COLUMN serial TEXT;
+ALTER TABLE photos ADD COLUMN iso INTEGER;
+ALTER TABLE photos ADD COLUMN shutter DOUBLE;
+ALTER TABLE photos ADD COLUMN aperture DOUBLE;
+ALTER TABLE photos ADD COLUMN focal_length DOUBLE;
+ALTER TABLE photos ADD COLUMN metadata_sources TEXT NOT NULL DEFAULT '{}';
@@ -0,0 +1,2 @@
+ALTER TABLE photos ADD COLUMN date_source TEXT;
+UPDATE photos SET date_source = 'exif' WHERE exif_date != 0;
@@ -0,0 +1,2 @@
+ALTER TABLE photos DROP COLUMN subsec_ms;
+ALTER TABLE photos DROP COLUMN utc_offset;
@@ -0,0 +1,3 @@
+-- exif_date is the UTC instant when utc_offset is set, and the camera wall time otherwise.
+ALTER TABLE photos ADD COLUMN utc_offset INTEGER;
+ALTER TABLE photos ADD COLUMN subsec_ms INTEGER;
@@ -0,0 +1,2 @@
+-- How current_path was created from original_path: copy, hard, reflink or symlink.
+ALTER TABLE photos ADD COLUMN link_type TEXT NOT NULL DEFAULT 'copy';
@@ -0,0 +1,8 @@
+-- The pixel hash of each source file seen by an import, so unchanged files are not decoded again.
+CREATE TABLE IF NOT EXISTS scan_cache (
+    path TEXT PRIMARY KEY NOT NULL,
+    size BIGINT NOT NULL,
+    mtime BIGINT NOT NULL,
+    inode BIGINT NOT NULL,
+    hash BIGINT NOT NULL
+);
@@ -0,0 +1,134 @@
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::photodb_error::PhotoDBError;
+
+/// Separates the archive from the path of an entry inside it, as in `archive.zip!/inner/a.NEF`.
+pub const ENTRY_SEPARATOR: &str = "!/";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Whether `path` is named like a zip, tar or gzipped tar archive.
+pub fn is_archive(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+/// The path recorded for an entry of an archive, e.g. `archive.zip!/inner/a.NEF`.
+pub fn entry_path(archive: &Path, inner: &Path) -> PathBuf {
+    PathBuf::from(format!("{}{}{}", archive.display(), ENTRY_SEPARATOR, inner.display()))
+}
+
+/// Splits an entry path into the archive and the path inside it, if it is one.
+pub fn split_entry_path(path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let path = path.to_str()?;
+    path.match_indices(ENTRY_SEPARATOR).find_map(|(i, _)| {
+        let archive = Path::new(&path[..i]);
+        (is_archive(archive) && archive.is_file())
+            .then(|| (archive.to_path_buf(), PathBuf::from(&path[i + ENTRY_SEPARATOR.len()..])))
+    })
+}
+
+pub fn is_entry_path(path: &Path) -> bool {
+    split_entry_path(path).is_some()
+}
+
+fn archive_error(archive: &Path, e: impl std::fmt::Display) -> PhotoDBError {
+    PhotoDBError::new(format!("reading archive: {}", e).as_str(), archive)
+}
+
+/// Streams the files of `archive` in the order they are stored. Entries for which `wanted`,
+/// given their path and size, returns false are skipped without being read; the others are read
+/// into memory one at a time and handed to `f`. Entries whose path would leave the archive, such
+/// as `../a.NEF`, are skipped.
+pub fn for_each_entry(
+    archive: &Path, mut wanted: impl FnMut(&Path, u64) -> bool, mut f: impl FnMut(PathBuf, Vec<u8>),
+) -> Result<(), PhotoDBError> {
+    let kind = kind(archive).ok_or_else(|| archive_error(archive, "unsupported archive type"))?;
+    let file = File::open(archive).map_err(|e| archive_error(archive, e))?;
+    match kind {
+        Kind::Zip => {
+            let mut zip = zip::ZipArchive::new(file).map_err(|e| archive_error(archive, e))?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i).map_err(|e| archive_error(archive, e))?;
+                let Some(inner) = entry.enclosed_name().map(Path::to_path_buf) else { continue };
+                let size = entry.size();
+                if entry.is_dir() || !wanted(&inner, size) {
+                    continue;
+                }
+                f(inner, read_all(&mut entry, size).map_err(|e| archive_error(archive, e))?);
+            }
+        }
+        Kind::Tar => for_each_tar_entry(archive, file, &mut wanted, &mut f)?,
+        Kind::TarGz => for_each_tar_entry(archive, GzDecoder::new(file), &mut wanted, &mut f)?,
+    }
+    Ok(())
+}
+
+fn for_each_tar_entry(
+    archive: &Path, reader: impl Read, wanted: &mut impl FnMut(&Path, u64) -> bool,
+    f: &mut impl FnMut(PathBuf, Vec<u8>),
+) -> Result<(), PhotoDBError> {
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries().map_err(|e| archive_error(archive, e))? {
+        let mut entry = entry.map_err(|e| archive_error(archive, e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let inner = entry.path().map_err(|e| archive_error(archive, e))?.into_owned();
+        if !is_enclosed(&inner) || !wanted(&inner, entry.size()) {
+            continue;
+        }
+        let size = entry.size();
+        f(inner, read_all(&mut entry, size).map_err(|e| archive_error(archive, e))?);
+    }
+    Ok(())
+}
+
+/// Whether an entry path stays inside the archive: relative, without `..`.
+fn is_enclosed(inner: &Path) -> bool {
+    inner
+        .components()
+        .all(|c| matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir))
+}
+
+fn read_all(reader: &mut impl Read, size: u64) -> Result<Vec<u8>, io::Error> {
+    let mut buf = Vec::with_capacity(size as usize);
+    reader.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads one entry, given its entry path. Zip entries are found from the archive's directory,
+/// tar archives have to be read through.
+pub fn read_entry(path: &Path) -> Result<Vec<u8>, PhotoDBError> {
+    let (archive, inner) =
+        split_entry_path(path).ok_or_else(|| PhotoDBError::new("not an archive entry", path))?;
+    let mut found = None;
+    for_each_entry(
+        &archive,
+        |entry, _| entry == inner,
+        |_, buf| {
+            found.get_or_insert(buf);
+        },
+    )?;
+    found.ok_or_else(|| PhotoDBError::new("no such entry in the archive", path))
+}
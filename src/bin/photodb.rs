@@ -1,184 +1,476 @@
 extern crate photodb;
-use clap::Parser;
-use photodb::db::build_config_path;
-use photodb::photodb_error::PhotoDBError;
-use photodb::{db, util};
+use chrono::{Duration, NaiveDateTime};
+use clap::{Args, Parser, Subcommand};
+use photodb::config::{LibraryConfig, Overrides, Replica};
+use photodb::events::{self, Category, Event, OutputFormat};
+use photodb::formats::Formats;
+use photodb::import_filter::{self, ImportFilter};
+use photodb::import_journal::ImportOptions;
+use photodb::transfer::LinkMode;
+use photodb::verify::VerifyMode;
+use photodb::watch::WatchOptions;
+use photodb::{
+    archive, clean, db, exif, import, library, plan, reorganize, sync, timeshift, verify,
+};
 
-use glob::{glob_with, MatchOptions};
-use photodb::raw_photo::Photo;
-use rayon::prelude::*;
-use rusqlite::*;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::{fs, path::PathBuf};
-
-use crate::util::is_image_file;
+use std::time::Duration as StdDuration;
 
 /// Simple photo database management tool. Pixel content based de-duplication via xxhash and libraw.
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct Cli {
-    /// The database root to move files into
-    #[clap(long, default_value = "photodb")]
+    /// The library root photos are stored under
+    #[clap(long, global = true, env = "PHOTODB_ROOT", default_value = "photodb")]
     pub db_root: PathBuf,
-    /// Move the files to the database root
-    #[clap(short, long, default_value_t = false)]
-    pub move_files: bool,
-    /// Import the files into the database, checking for duplicates
-    #[clap(short, long, default_value_t = false)]
-    pub insert: bool,
+    /// The database file. Defaults to the library config's, or .photodb/photo.db under the library root
+    #[clap(long, global = true, value_name = "FILE")]
+    pub database: Option<PathBuf>,
+    /// Number of threads to hash and copy files with. Defaults to one per CPU
+    #[clap(long, global = true, value_name = "N")]
+    pub threads: Option<usize>,
+    /// Number of files to copy into the library at once. Defaults to the number of threads
+    #[clap(long, global = true, value_name = "N")]
+    pub io: Option<usize>,
+    /// Print human readable lines or one JSON object per event
+    #[clap(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+    /// Write a JSON report of the run and its failures to this file
+    #[clap(long, global = true)]
+    pub report: Option<PathBuf>,
     /// Create the database
-    #[clap(short, long, default_value_t = false)]
+    #[clap(short, long, global = true, default_value_t = false)]
     pub create: bool,
-    /// The path to the file or directory to read
-    path: PathBuf,
+    #[command(subcommand)]
+    command: Commands,
 }
 
-fn import_directory(
-    path_to_import: &PathBuf, import_path: &PathBuf, move_file: bool, insert: bool,
-    database: &PathBuf,
-) {
-    if !path_to_import.is_dir() {
-        println!("{} is not a directory", path_to_import.display());
-        exit(1);
-    }
+#[derive(Subcommand)]
+enum Commands {
+    /// Import files into the database
+    Import {
+        /// The path to the file or directory to read. With --from-stdin, the folder the include and
+        /// exclude globs are relative to
+        #[clap(required_unless_present_any = ["resume", "from_stdin"])]
+        path: Option<PathBuf>,
+        /// Finish an interrupted import from its journal, with the options it was started with
+        #[clap(long, value_name = "ID", conflicts_with_all = ["path", "from_stdin"])]
+        resume: Option<String>,
+        /// Import exactly the files listed on stdin, one path per line, instead of walking a folder
+        #[clap(long, default_value_t = false)]
+        from_stdin: bool,
+        /// Paths on stdin are separated by NUL bytes, as printed by `find -print0`
+        #[clap(short = '0', long, default_value_t = false, requires = "from_stdin")]
+        null: bool,
+        /// Write the operations the import would run to this file instead of running them. Needs
+        /// --copy and --insert together, as a planned placement both copies and commits a photo
+        #[clap(long, value_name = "FILE", conflicts_with = "resume")]
+        plan: Option<PathBuf>,
+        #[command(flatten)]
+        import: ImportArgs,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Import every image dropped into a folder, until stopped with SIGTERM or SIGINT
+    Watch {
+        /// The folder to watch
+        path: PathBuf,
+        /// Seconds a file's size has to stay the same before it is imported
+        #[clap(long, value_name = "SECS", default_value_t = 5)]
+        settle: u64,
+        /// Seconds to wait for more files before importing the ones that are ready
+        #[clap(long, value_name = "SECS", default_value_t = 10)]
+        batch: u64,
+        #[command(flatten)]
+        import: ImportArgs,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Run the operations of a plan written by import, sync or clean, if nothing changed since
+    Apply {
+        /// The plan file
+        plan: PathBuf,
+    },
+    /// Check the library against the database
+    Verify {
+        /// What to check
+        #[arg(value_enum, default_value_t = VerifyMode::Hash)]
+        mode: VerifyMode,
+    },
+    /// Copy the photos of the library that are missing from another library into it, for merging
+    /// libraries or for backups
+    Sync {
+        /// The root of the library to copy the missing photos into, or the name of one of the
+        /// library's replicas. Every replica when not given
+        target: Option<PathBuf>,
+        /// Copy the missing photos. If this is not set, they are only printed
+        #[clap(long, alias = "do-sync", default_value_t = false)]
+        apply: bool,
+        /// Write the copies the sync would make to this file for `photodb apply` instead of running them
+        #[clap(long, value_name = "FILE", conflicts_with = "apply", requires = "target")]
+        plan: Option<PathBuf>,
+        /// Also copy extended attributes, such as Finder tags
+        #[clap(long, default_value_t = false)]
+        xattrs: bool,
+    },
+    /// Delete the source files under a folder that were imported into the library
+    Clean {
+        /// The folder to clean
+        target: PathBuf,
+        /// Delete the files. If this is not set, they are only printed
+        #[clap(short, long, default_value_t = false)]
+        delete: bool,
+        /// Write the deletions to this file for `photodb apply` instead of running them
+        #[clap(long, value_name = "FILE", conflicts_with = "delete")]
+        plan: Option<PathBuf>,
+    },
+    /// Print the exif tags of an image or of every image in a directory
+    Exif {
+        /// The image or directory to read
+        path: PathBuf,
+        /// Only print the date tags
+        #[clap(short, long, default_value_t = false)]
+        date_only: bool,
+    },
+    /// Inspect the library's settings
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Generate the thumbnail cache for every photo in the database
+    Thumbs {
+        /// Delete the existing cache and regenerate every thumbnail
+        #[clap(long, default_value_t = false)]
+        rebuild: bool,
+    },
+    /// List photos whose capture date was guessed from the file name, folder or mtime
+    WeakDates,
+    /// Move every photo to where the current layout puts it, removing emptied directories
+    Reorganize {
+        /// Apply the plan without asking for confirmation
+        #[clap(short, long, default_value_t = false)]
+        yes: bool,
+    },
+    /// Find where a photo imported from a file name or path is stored in the library
+    Lookup {
+        /// The original file name, e.g. _DSC1234.ARW, or the full original path
+        name: String,
+    },
+    /// Correct the capture time of photos taken with a wrongly set camera clock
+    Timeshift {
+        /// Only shift photos taken with this camera model
+        #[clap(long)]
+        model: Option<String>,
+        /// Only shift photos taken with this camera body serial number
+        #[clap(long)]
+        serial: Option<String>,
+        /// Only shift photos taken between these two camera clock times
+        #[clap(long, num_args = 2, value_names = ["FROM", "TO"], value_parser = timeshift::parse_local_time)]
+        between: Option<Vec<NaiveDateTime>>,
+        /// Shift every photo in the library. One of --model, --serial, --between, --reference or
+        /// this is required, so that a missing selector does not shift everything
+        #[clap(long, default_value_t = false, conflicts_with_all = ["model", "serial", "between"])]
+        all: bool,
+        /// How far to move the capture times, e.g. +5h30m or -1d
+        #[clap(long, allow_hyphen_values = true, value_parser = timeshift::parse_shift, required_unless_present = "reference", conflicts_with = "reference")]
+        by: Option<Duration>,
+        /// A photo in the database whose true capture time is known
+        #[clap(long, requires = "true_time")]
+        reference: Option<PathBuf>,
+        /// The true capture time of the reference photo
+        #[clap(long, value_parser = timeshift::parse_local_time)]
+        true_time: Option<NaiveDateTime>,
+        /// Also write the corrected capture times to XMP sidecars
+        #[clap(long, default_value_t = false)]
+        write_xmp: bool,
+        /// Update the database and move the files. If this is not set, the changes are only printed
+        #[clap(long, default_value_t = false)]
+        apply: bool,
+    },
+}
 
-    let img_files = get_img_file_list(path_to_import);
-    let total_files = img_files.len();
-    println!("Importing {} files", total_files);
-    let photo_vec = get_photos_from_img_file_list(&img_files, import_path);
-    let hashed = &photo_vec.len();
-    println!("Hashed {}/{} files", hashed, total_files);
-    let copy_list: Vec<Photo> = photo_vec
-        .into_iter()
-        .filter(|photo| !db::is_imported(photo.hash, database))
-        .filter_map(|photo| {
-            if insert {
-                db::insert_file_to_db(&photo, database)
-                    .map_err(|e| {
-                        println!(
-                            "{}",
-                            PhotoDBError::new(
-                                format!("inserting file: {}", e).as_str(),
-                                &photo.og_path
-                            )
-                        );
-                    })
-                    .ok()
-                    .and_then(|_| {
-                        println!("inserted file: {} -> {}", photo.og_path.display(), photo.hash);
-                        Some(photo)
-                    })
-            } else {
-                println!(
-                    "mock inserted file: {} -> {}",
-                    photo.og_path.display(),
-                    photo.db_path.display()
-                );
-                Some(photo)
-            }
-        })
-        .collect();
-    println!("{}/{} files to copy", copy_list.len(), total_files);
-    let copied: u64;
-    if move_file {
-        copied = copy_list
-            .par_iter()
-            .map(|photo| {
-                //check if photo.db_path exists, create it if it does not
-                if !photo.db_path.parent().unwrap().exists() {
-                    fs::create_dir_all(photo.db_path.parent().unwrap())
-                        .map_err(|e| {
-                            println!(
-                                "{}",
-                                PhotoDBError::new(
-                                    format!("creating directory: {}", e).as_str(),
-                                    &photo.og_path
-                                )
-                            );
-                            return 0;
-                        })
-                        .ok();
-                }
-                fs::copy(&photo.og_path, &photo.db_path)
-                    .map_err(|e| {
-                        println!(
-                            "{}",
-                            PhotoDBError::new(
-                                format!("copying file: {}", e).as_str(),
-                                &photo.og_path
-                            )
-                        );
-                        return 0;
-                    })
-                    .ok();
-                println!(
-                    "copied file: {} -> {}",
-                    &photo.og_path.display(),
-                    &photo.db_path.display()
-                );
-                1
-            })
-            .collect::<Vec<u64>>()
-            .par_iter()
-            .sum();
-        println!("Copied {}/{} files", copied, copy_list.len());
-    } else {
-        copied = copy_list
-            .par_iter()
-            .map(|photo| {
-                println!(
-                    "mock copied file: {} -> {}",
-                    &photo.og_path.display(),
-                    &photo.db_path.display()
-                );
-                1
-            })
-            .collect::<Vec<u64>>()
-            .par_iter()
-            .sum();
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective settings and where each one comes from: the command line, the
+    /// environment, the library config, the user config or the defaults
+    Show,
+}
+
+/// How an import puts files into the library.
+#[derive(Args)]
+struct ImportArgs {
+    /// Copy (or link, see --link) the files into the database root. Sources are kept unless
+    /// --delete-source is also given
+    #[clap(long = "copy", default_value_t = false)]
+    copy_files: bool,
+    /// Delete each source file once its copy in the database root is verified
+    #[clap(long, default_value_t = false, requires_all = ["copy_files", "insert"])]
+    delete_source: bool,
+    /// How files are put into the database root
+    #[clap(long, value_enum, default_value_t = LinkMode::Copy)]
+    link: LinkMode,
+    /// Also copy extended attributes, such as Finder tags
+    #[clap(long, default_value_t = false)]
+    xattrs: bool,
+    /// Import the files into the database, checking for duplicates
+    #[clap(short, long, default_value_t = false)]
+    insert: bool,
+    /// Hash every file again instead of skipping unchanged files that were already imported
+    #[clap(long, default_value_t = false)]
+    rescan: bool,
+}
+
+/// Which files an import picks up, besides the ones `.photodbignore` files exclude.
+#[derive(Args)]
+struct FilterArgs {
+    /// Only import photos taken at or after this time, e.g. 2023-06-01 or "2023-06-01 12:00:00"
+    #[clap(long, value_parser = import_filter::parse_since)]
+    since: Option<NaiveDateTime>,
+    /// Only import photos taken at or before this time. A bare day includes the whole day
+    #[clap(long, value_parser = import_filter::parse_until)]
+    until: Option<NaiveDateTime>,
+    /// Only import photos taken with this camera model
+    #[clap(long)]
+    model: Option<String>,
+    /// Only import photos taken with this camera body serial number
+    #[clap(long)]
+    serial: Option<String>,
+    /// Only import files whose path relative to the import folder matches this glob. Repeatable
+    #[clap(long, value_name = "GLOB", value_parser = import_filter::parse_glob)]
+    include: Vec<glob::Pattern>,
+    /// Skip files whose path relative to the import folder matches this glob, e.g. "**/_rejects/**". Repeatable
+    #[clap(long, value_name = "GLOB", value_parser = import_filter::parse_glob)]
+    exclude: Vec<glob::Pattern>,
+    /// Skip files smaller than this size, e.g. 5M
+    #[clap(long, value_name = "SIZE", value_parser = import_filter::parse_size)]
+    min_size: Option<u64>,
+    /// Skip files larger than this size, e.g. 200M
+    #[clap(long, value_name = "SIZE", value_parser = import_filter::parse_size)]
+    max_size: Option<u64>,
+}
+
+impl FilterArgs {
+    fn to_filter(&self) -> ImportFilter {
+        ImportFilter {
+            since: self.since,
+            until: self.until,
+            model: self.model.clone(),
+            serial: self.serial.clone(),
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            min_size: self.min_size,
+            max_size: self.max_size,
+        }
     }
-    println!("Copied {}/{} files", copied, copy_list.len());
 }
 
-fn get_img_file_list(path_to_import: &PathBuf) -> Vec<PathBuf> {
-    let options: MatchOptions = Default::default();
-    let img_files: Vec<_> =
-        glob_with(path_to_import.join("**/*").as_os_str().to_str().expect("join"), options)
-            .unwrap()
-            .filter_map(|x| x.ok())
-            .filter_map(|path| is_image_file(&path).then_some(path))
-            .collect();
-    img_files
+/// The options of an import from `source`, as given on the command line.
+fn import_options(source: PathBuf, args: &ImportArgs) -> ImportOptions {
+    if args.delete_source && args.link == LinkMode::Symlink {
+        let message = "--delete-source would leave the symlinks in the library dangling";
+        events::fatal(Event::error(Category::Input, Path::new(""), message));
+    }
+    ImportOptions {
+        source,
+        copy_files: args.copy_files,
+        insert: args.insert,
+        delete_source: args.delete_source,
+        link: args.link.as_str().to_string(),
+        xattrs: args.xattrs,
+    }
 }
 
-fn get_photos_from_img_file_list(img_files: &Vec<PathBuf>, import_path: &PathBuf) -> Vec<Photo> {
-    let photo_vec: Vec<Photo> = img_files
-        .par_iter()
-        .filter_map(|path| {
-            fs::read(path)
-                .map_err(|e| {
-                    println!(
-                        "{}",
-                        PhotoDBError::new(format!("reading file: {}", e).as_str(), &path)
-                    );
-                })
-                .as_ref()
-                .ok()
-                .and_then(|buf| Photo::new(buf, path, import_path).ok())
-            //Some(Photo::new(&buf, &path, import_path))
-        })
-        .collect();
-    photo_vec
+/// The plan file of an import. A planned placement copies the file and commits its row, so a plan
+/// cannot do only one of the two.
+fn import_plan_path<'a>(plan: &'a Option<PathBuf>, args: &ImportArgs) -> Option<&'a PathBuf> {
+    let plan = plan.as_ref()?;
+    if args.copy_files != args.insert {
+        let message = "--plan needs both --copy and --insert, or neither";
+        events::fatal(Event::error(Category::Input, plan, message));
+    }
+    Some(plan)
 }
 
 fn main() {
     let args = Cli::parse();
-    let db_path = build_config_path(&args.db_root);
+    events::set_output(args.output);
+    events::set_report(args.report.clone());
+    let overrides =
+        Overrides { database: args.database.clone(), threads: args.threads, io: args.io };
+    match &args.command {
+        // Plans name the library they were written for.
+        Commands::Apply { plan } => plan::apply_plan_file(plan, &overrides),
+        Commands::Config { command: ConfigCommand::Show } => {
+            let settings = LibraryConfig::effective_settings(&args.db_root, &overrides)
+                .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+            events::note(format!("Settings of {}", args.db_root.display()));
+            for setting in settings {
+                events::emit(Event::Setting {
+                    key: setting.key,
+                    value: setting.value,
+                    source: setting.source.as_str().to_string(),
+                });
+            }
+        }
+        command => {
+            let config = LibraryConfig::resolve(&args.db_root, &overrides)
+                .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+            config.apply_limits();
+            match command {
+                Commands::Exif { path, date_only } => {
+                    exif::print_exif_tags(path, *date_only, &Formats::new(&config.formats))
+                }
+                command => run_library_command(command, &args, &config),
+            }
+        }
+    }
+    exit(events::finish());
+}
+
+fn run_library_command(command: &Commands, args: &Cli, config: &LibraryConfig) {
+    let db_root = &args.db_root;
+    let db_path = config.database_path(db_root);
     if args.create {
-        fs::create_dir_all(db_path.parent().unwrap()).unwrap();
-        let mut conn = Connection::open(&db_path).unwrap();
-        db::create_table(&mut conn);
+        let dir = db_path.parent().unwrap_or(db_root);
+        fs::create_dir_all(dir).unwrap_or_else(|e| {
+            let message = format!("creating database directory: {}", e);
+            events::fatal(Event::error(Category::Input, dir, message))
+        });
+    }
+    let pool = db::get_connection_pool_for(&db_path)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let template = config.layout.path_template();
+    let formats = Formats::new(&config.formats);
+    match command {
+        Commands::Import { resume: Some(id), .. } => {
+            import::resume_import(id, db_root, &pool, &template)
+        }
+        Commands::Import { path, plan, import, filter, from_stdin: true, null, .. } => {
+            // Journals of piped imports are matched to the folder they were started in.
+            let source = path.clone().unwrap_or_else(|| {
+                std::env::current_dir().unwrap_or_else(|e| {
+                    events::fatal(Event::error(Category::Input, Path::new("."), e))
+                })
+            });
+            import::import_path_list(
+                &import_options(source, import),
+                &filter.to_filter(),
+                *null,
+                db_root,
+                import.rescan,
+                import_plan_path(plan, import),
+                &pool,
+                &formats,
+                &template,
+            )
+        }
+        Commands::Import { path: Some(path), plan, import, filter, .. }
+            if path.is_file() && archive::is_archive(path) =>
+        {
+            if plan.is_some() {
+                let message = "plans cannot be written for archives";
+                events::fatal(Event::error(Category::Input, path, message));
+            }
+            let mut options = import_options(path.clone(), import);
+            if import.link != LinkMode::Copy {
+                events::note("Archive entries can only be copied, ignoring --link");
+                options.link = LinkMode::Copy.as_str().to_string();
+            }
+            import::import_archive(
+                &options,
+                &filter.to_filter(),
+                db_root,
+                &pool,
+                &formats,
+                &template,
+            )
+        }
+        Commands::Import { path, plan, import, filter, .. } => {
+            let source = path.clone().expect("clap requires a path without --resume");
+            import::import_directory(
+                &import_options(source, import),
+                &filter.to_filter(),
+                db_root,
+                import.rescan,
+                import_plan_path(plan, import),
+                &pool,
+                &formats,
+                &template,
+            )
+        }
+        Commands::Watch { path, settle, batch, import, filter } => {
+            let watch_options = WatchOptions {
+                settle: StdDuration::from_secs(*settle),
+                batch: StdDuration::from_secs(*batch),
+            };
+            import::watch_directory(
+                &import_options(path.clone(), import),
+                &filter.to_filter(),
+                &watch_options,
+                db_root,
+                import.rescan,
+                &pool,
+                &formats,
+                &template,
+            )
+        }
+        Commands::Verify { mode } => verify::verify(*mode, db_root, &pool),
+        Commands::Sync { target, apply, plan, xattrs } => {
+            let replica = |replica: &Replica| (replica.root.clone(), *xattrs || replica.xattrs);
+            let targets: Vec<(PathBuf, bool)> = match target {
+                Some(target) => vec![config
+                    .replicas
+                    .iter()
+                    .find(|r| target.as_os_str() == r.name.as_str())
+                    .map_or((target.clone(), *xattrs), replica)],
+                None if config.replicas.is_empty() => {
+                    let message = "no target given and the library has no replicas";
+                    events::fatal(Event::error(Category::Input, db_root, message))
+                }
+                None => config.replicas.iter().map(replica).collect(),
+            };
+            for (target, xattrs) in targets.iter() {
+                sync::sync_libraries(db_root, &pool, target, *apply, plan.as_ref(), *xattrs)
+            }
+        }
+        Commands::Clean { target, delete, plan } => {
+            clean::clean_sources(db_root, target, *delete, plan.as_ref(), &pool)
+        }
+        Commands::Thumbs { rebuild } => library::build_thumbnails(db_root, *rebuild, &pool),
+        Commands::WeakDates => library::report_weak_dates(&pool),
+        Commands::Reorganize { yes } => {
+            reorganize::reorganize_library(db_root, *yes, &pool, &template)
+        }
+        Commands::Lookup { name } => library::lookup_original(name, &pool),
+        Commands::Timeshift {
+            model,
+            serial,
+            between,
+            all,
+            by,
+            reference,
+            true_time,
+            write_xmp,
+            apply,
+        } => timeshift::shift_photos(
+            db_root,
+            model.clone(),
+            serial.clone(),
+            between.clone(),
+            *all,
+            *by,
+            reference.clone(),
+            *true_time,
+            *write_xmp,
+            *apply,
+            &pool,
+            &template,
+        ),
+        Commands::Exif { .. } | Commands::Apply { .. } | Commands::Config { .. } => {
+            unreachable!("run without a library")
+        }
     }
-    import_directory(&args.path, &args.db_root, args.move_files, args.insert, &db_path)
 }
@@ -0,0 +1,98 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::models::Photo;
+use crate::photodb_error::PhotoDBError;
+use crate::plan::Plan;
+use crate::transfer::{self, LinkMode};
+
+/// Fails for rows whose library file is a symlink to the source, which deleting the source would
+/// leave dangling.
+fn check_deletable(
+    db_row: &Photo, og_path: &PathBuf, db_path: &PathBuf,
+) -> Result<(), PhotoDBError> {
+    if LinkMode::from_db(&db_row.link_type) == LinkMode::Symlink {
+        return Err(PhotoDBError::new("imported as a symlink, keeping source", og_path));
+    }
+    transfer::check_independent_copy(og_path, db_path)
+}
+
+/// Deletes the sources under `target` that were imported into the library at `db_root` and
+/// whose library copy still exists. Only prints them unless `delete` is set, or writes the
+/// deletions to `plan_path` for `photodb apply`.
+pub fn clean_sources(
+    db_root: &Path, target: &Path, delete: bool, plan_path: Option<&PathBuf>,
+    pool: &Pool<ConnectionManager<SqliteConnection>>,
+) {
+    events::note(format!("Cleaning {} from {}", target.display(), db_root.display()));
+    //Filter the database for original paths matching the target folder:
+    let matches = db::get_photos_by_original_prefix(target, pool)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    events::note(format!("Found {} matches", matches.len()));
+    if let Some(plan_path) = plan_path {
+        let mut plan = Plan::new("clean", db_root, false);
+        for db_row in matches.iter() {
+            let (og_path, db_path) =
+                (PathBuf::from(&db_row.original_path), PathBuf::from(&db_row.current_path));
+            if og_path.exists() && db_path.exists() {
+                check_deletable(db_row, &og_path, &db_path)
+                    .and_then(|_| plan.delete_source(&og_path, db_row.hash, &db_path))
+                    .map_err(|e| events::emit(Event::failed(Category::Read, &e)))
+                    .ok();
+            }
+        }
+        plan.write(plan_path).unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+        events::note(format!(
+            "Wrote {} operations to {}, run `photodb apply {}` to run them",
+            plan.operations.len(),
+            plan_path.display(),
+            plan_path.display()
+        ));
+        events::summary(
+            "clean",
+            vec![("matches", matches.len()), ("planned", plan.operations.len())],
+        );
+        return;
+    }
+    //Delete the original paths from the filesystem:
+    let deleted = matches
+        .par_iter()
+        .filter(|db_row| {
+            let db_path = PathBuf::from(&db_row.current_path);
+            let og_path = PathBuf::from(&db_row.original_path);
+            if !db_path.exists() {
+                events::emit(Event::error(
+                    Category::Read,
+                    &og_path,
+                    "library copy is missing, keeping source",
+                ));
+                return false;
+            }
+            if !og_path.exists() {
+                events::note(format!("{} already deleted", og_path.display()));
+                return false;
+            }
+            if let Err(e) = check_deletable(db_row, &og_path, &db_path) {
+                events::emit(Event::failed(Category::Delete, &e));
+                return false;
+            }
+            if delete {
+                if let Err(e) = std::fs::remove_file(&og_path) {
+                    events::emit(Event::error(
+                        Category::Delete,
+                        &og_path,
+                        format!("deleting file: {}", e),
+                    ));
+                    return false;
+                }
+            }
+            events::emit(Event::Deleted { path: events::path(&og_path), dry_run: !delete });
+            true
+        })
+        .count();
+    events::summary("clean", vec![("matches", matches.len()), ("deleted", deleted)]);
+}
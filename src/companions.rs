@@ -0,0 +1,82 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::photodb_error::PhotoDBError;
+
+/// Extensions of files that belong to the raw with the same stem: XMP sidecars and the JPEG or
+/// HEIF the camera wrote next to it.
+const COMPANION_EXTENSIONS: [&str; 5] = ["xmp", "jpg", "jpeg", "heic", "heif"];
+
+/// Sidecars and paired images next to `raw`: `stem.xmp`, `stem.JPG`, ... and `name.ext.xmp`.
+pub fn find_companions(raw: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(stem), Some(name)) = (raw.parent(), raw.file_stem(), raw.file_name())
+    else {
+        return Vec::new();
+    };
+    let mut sidecar_name = name.to_owned();
+    sidecar_name.push(".xmp");
+    let is_companion = |path: &Path| {
+        let same_stem = path.file_stem() == Some(stem)
+            && path
+                .extension()
+                .and_then(OsStr::to_str)
+                .map(|ext| COMPANION_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                .unwrap_or(false);
+        same_stem || path.file_name() == Some(sidecar_name.as_os_str())
+    };
+    let mut companions: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.as_path() != raw && path.is_file() && is_companion(path))
+                .collect()
+        })
+        .unwrap_or_default();
+    companions.sort();
+    companions
+}
+
+/// Where `companion` of `raw` belongs once the raw is stored at `dest`, so it keeps matching the
+/// raw's (possibly renamed) file name.
+pub fn companion_destination(companion: &Path, raw: &Path, dest: &Path) -> PathBuf {
+    let raw_name = raw.file_name().unwrap_or_default().to_string_lossy();
+    let companion_name = companion.file_name().unwrap_or_default().to_string_lossy();
+    match companion_name.strip_prefix(raw_name.as_ref()) {
+        // name.ext.xmp
+        Some(suffix) if !suffix.is_empty() => {
+            let mut name = dest.file_name().unwrap_or_default().to_owned();
+            name.push(suffix);
+            dest.with_file_name(name)
+        }
+        _ => dest.with_extension(companion.extension().unwrap_or_default()),
+    }
+}
+
+/// Moves `from` to `to` along with its companions, recording every rename in `moved`. Files an
+/// interrupted earlier run already moved are skipped, so a move can be repeated until it succeeds.
+pub fn move_with_companions(
+    from: &Path, to: &Path, moved: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), PhotoDBError> {
+    let mut pairs = Vec::new();
+    if from.exists() {
+        fs::create_dir_all(to.parent().unwrap())
+            .map_err(|e| PhotoDBError::new(format!("creating directory: {}", e).as_str(), to))?;
+        pairs.push((from.to_path_buf(), to.to_path_buf()));
+    } else if !to.exists() {
+        return Err(PhotoDBError::new("file not found", from));
+    }
+    for companion in find_companions(from) {
+        let dest = companion_destination(&companion, from, to);
+        pairs.push((companion, dest));
+    }
+    for (src, dst) in pairs {
+        if dst.exists() {
+            return Err(PhotoDBError::new("destination already exists", &dst));
+        }
+        fs::rename(&src, &dst)
+            .map_err(|e| PhotoDBError::new(format!("moving file: {}", e).as_str(), &src))?;
+        moved.push((src, dst));
+    }
+    Ok(())
+}
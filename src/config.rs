@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::db::build_config_path;
+use crate::path_template::PathTemplate;
+use crate::photodb_error::PhotoDBError;
+use crate::transfer;
+
+/// Overrides the database file of every library.
+pub const ENV_DATABASE: &str = "PHOTODB_DATABASE";
+/// Overrides `limits.threads`.
+pub const ENV_THREADS: &str = "PHOTODB_THREADS";
+/// Overrides `limits.io`.
+pub const ENV_IO: &str = "PHOTODB_IO";
+
+/// Per-library settings, read from `.photodb/photodb.toml` under the database root on top of the
+/// user's `photodb.toml`. The environment and the command line override both, see
+/// [`LibraryConfig::resolve`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LibraryConfig {
+    /// The database file, relative to the database root. `.photodb/photo.db` when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<PathBuf>,
+    pub formats: FormatConfig,
+    pub layout: LayoutConfig,
+    pub limits: LimitConfig,
+    pub hash: HashConfig,
+    /// Other libraries `photodb sync` copies this one to
+    pub replicas: Vec<Replica>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FormatConfig {
+    /// Extensions to import on top of the ones libraw supports
+    pub include: Vec<String>,
+    /// Extensions to skip even though libraw supports them
+    pub exclude: Vec<String>,
+    /// Check the header of files with unknown or missing extensions for raw magic numbers
+    pub sniff: bool,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        FormatConfig { include: Vec::new(), exclude: Vec::new(), sniff: true }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LayoutConfig {
+    /// Where photos are stored under the database root, see [`PathTemplate`]
+    pub template: PathTemplate,
+    /// File name pattern applied on import in place of `{filename}`, e.g.
+    /// `{date:%Y%m%d-%H%M%S}-{subsec}-{model}-{seq}.{ext}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rename: Option<PathTemplate>,
+}
+
+impl LayoutConfig {
+    /// The layout template with the rename pattern applied.
+    pub fn path_template(&self) -> PathTemplate {
+        match &self.rename {
+            Some(rename) => self.template.with_rename(rename),
+            None => self.template.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// Threads to hash and copy files with. One per CPU when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+    /// Files copied into a library at once, for disks that slow down under parallel writes. As
+    /// many as there are threads when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HashConfig {
+    /// How the hashes in the database were computed
+    pub scheme: HashScheme,
+}
+
+/// A way of hashing photos. A library naming a scheme this build does not know is refused, so
+/// its photos are never compared with hashes computed another way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashScheme {
+    /// xxh3 of the decoded raw pixels, so metadata edits keep the hash
+    #[default]
+    Xxh3Pixels,
+}
+
+/// Another library kept in sync with this one, named so `photodb sync <name>` can find it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Replica {
+    pub name: String,
+    /// The replica's database root
+    pub root: PathBuf,
+    /// Also copy extended attributes to it
+    #[serde(default)]
+    pub xattrs: bool,
+}
+
+pub fn build_library_config_path(db_root: &Path) -> PathBuf {
+    db_root.join(".photodb").join("photodb.toml")
+}
+
+/// The user's config, `photodb/photodb.toml` under `$XDG_CONFIG_HOME` or `~/.config`, which
+/// holds the defaults of every library.
+pub fn user_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("photodb").join("photodb.toml"))
+}
+
+/// Where a setting came from, from the lowest to the highest precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Default,
+    User,
+    Library,
+    Env,
+    Cli,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Default => "default",
+            Source::User => "user config",
+            Source::Library => "library config",
+            Source::Env => "environment",
+            Source::Cli => "command line",
+        }
+    }
+}
+
+/// Settings given on the command line or in the environment, which win over both config files.
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub database: Option<PathBuf>,
+    pub threads: Option<usize>,
+    pub io: Option<usize>,
+}
+
+impl Overrides {
+    /// The overrides set by `PHOTODB_DATABASE`, `PHOTODB_THREADS` and `PHOTODB_IO`.
+    pub fn from_env() -> Result<Self, PhotoDBError> {
+        let number = |name: &str| match env::var(name) {
+            Ok(value) => value.trim().parse().map(Some).map_err(|_| {
+                let message = format!("{} must be a number, not '{}'", name, value);
+                PhotoDBError::new(message.as_str(), &PathBuf::new())
+            }),
+            Err(_) => Ok(None),
+        };
+        Ok(Overrides {
+            database: env::var_os(ENV_DATABASE).filter(|v| !v.is_empty()).map(PathBuf::from),
+            threads: number(ENV_THREADS)?,
+            io: number(ENV_IO)?,
+        })
+    }
+
+    /// The overrides as a config file would set them. A relative database path is taken from the
+    /// working directory rather than the database root.
+    fn to_table(&self) -> toml::Table {
+        let mut table = toml::Table::new();
+        if let Some(database) = &self.database {
+            let database = env::current_dir().map_or(database.clone(), |cwd| cwd.join(database));
+            let database = database.to_string_lossy().to_string();
+            table.insert("database".to_string(), toml::Value::String(database));
+        }
+        let mut limits = toml::Table::new();
+        for (key, value) in [("threads", self.threads), ("io", self.io)] {
+            if let Some(value) = value {
+                limits.insert(key.to_string(), toml::Value::Integer(value as i64));
+            }
+        }
+        if !limits.is_empty() {
+            table.insert("limits".to_string(), toml::Value::Table(limits));
+        }
+        table
+    }
+}
+
+/// Reads a config file as a table, checking that it holds valid settings. `None` when there is
+/// no such file.
+fn read_table(path: &PathBuf) -> Result<Option<toml::Table>, PhotoDBError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|e| PhotoDBError::new(format!("reading config: {}", e).as_str(), path))?;
+    let parse_error =
+        |e: toml::de::Error| PhotoDBError::new(format!("parsing config: {}", e).as_str(), path);
+    toml::from_str::<LibraryConfig>(&contents).map_err(parse_error)?.check(path)?;
+    toml::from_str(&contents).map(Some).map_err(parse_error)
+}
+
+/// The config files that apply to the library at `db_root`, from the lowest to the highest
+/// precedence.
+fn file_layers(db_root: &Path) -> Result<Vec<(Source, toml::Table)>, PhotoDBError> {
+    let mut layers = Vec::new();
+    if let Some(table) = user_config_path().map(|path| read_table(&path)).transpose()?.flatten() {
+        layers.push((Source::User, table));
+    }
+    if let Some(table) = read_table(&build_library_config_path(db_root))? {
+        layers.push((Source::Library, table));
+    }
+    Ok(layers)
+}
+
+/// Every layer of settings for the library at `db_root`, from the lowest to the highest
+/// precedence.
+fn layers(db_root: &Path, cli: &Overrides) -> Result<Vec<(Source, toml::Table)>, PhotoDBError> {
+    let mut layers = file_layers(db_root)?;
+    layers.push((Source::Env, Overrides::from_env()?.to_table()));
+    layers.push((Source::Cli, cli.to_table()));
+    Ok(layers)
+}
+
+/// Copies the keys of `over` into `base`, merging tables key by key. Other values, lists
+/// included, replace the ones in `base`.
+fn merge(base: &mut toml::Table, over: &toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn contains(table: &toml::Table, key: &str) -> bool {
+    match key.split_once('.') {
+        Some((first, rest)) => match table.get(first) {
+            Some(toml::Value::Table(table)) => contains(table, rest),
+            _ => false,
+        },
+        None => table.contains_key(key),
+    }
+}
+
+/// The dotted keys of every value in `table` that is not itself a table.
+fn flatten(prefix: &str, table: &toml::Table, settings: &mut Vec<(String, toml::Value)>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::Table(table) => flatten(&key, table, settings),
+            value => settings.push((key, value.clone())),
+        }
+    }
+}
+
+/// One effective setting and where it came from.
+#[derive(Clone, Debug)]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+    pub source: Source,
+}
+
+impl LibraryConfig {
+    /// Loads the settings of the library at `db_root`: the user config, overridden by the
+    /// library config, the environment and `cli` in turn.
+    pub fn resolve(db_root: &Path, cli: &Overrides) -> Result<Self, PhotoDBError> {
+        Self::from_layers(db_root, &layers(db_root, cli)?)
+    }
+
+    /// Loads the settings of the library at `db_root` from the config files alone, for a library
+    /// other than the one the command line and environment are about, such as a sync target.
+    pub fn load(db_root: &Path) -> Result<Self, PhotoDBError> {
+        Self::from_layers(db_root, &file_layers(db_root)?)
+    }
+
+    fn from_layers(db_root: &Path, layers: &[(Source, toml::Table)]) -> Result<Self, PhotoDBError> {
+        let mut merged = toml::Table::new();
+        for (_, table) in layers {
+            merge(&mut merged, table);
+        }
+        let path = build_library_config_path(db_root);
+        toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| PhotoDBError::new(format!("parsing config: {}", e).as_str(), &path))
+    }
+
+    fn check(self, path: &Path) -> Result<Self, PhotoDBError> {
+        match &self.layout.rename {
+            Some(rename) if !rename.is_file_name() => Err(PhotoDBError::new(
+                "parsing config: layout.rename must be a file name without '/'",
+                path,
+            )),
+            _ => Ok(self),
+        }
+    }
+
+    /// The database file of the library at `db_root`.
+    pub fn database_path(&self, db_root: &Path) -> PathBuf {
+        match &self.database {
+            Some(database) => db_root.join(database),
+            None => build_config_path(db_root),
+        }
+    }
+
+    /// Sizes the global thread pool and limits the copies run at once. The thread pool can only
+    /// be sized before it is first used.
+    pub fn apply_limits(&self) {
+        if let Some(threads) = self.limits.threads {
+            rayon::ThreadPoolBuilder::new().num_threads(threads).build_global().ok();
+        }
+        transfer::set_io_limit(self.limits.io.unwrap_or(0));
+    }
+
+    /// Every setting that applies to the library at `db_root`, with where it came from, as
+    /// `photodb config show` prints them.
+    pub fn effective_settings(
+        db_root: &Path, cli: &Overrides,
+    ) -> Result<Vec<Setting>, PhotoDBError> {
+        let layers = layers(db_root, cli)?;
+        let mut config = Self::from_layers(db_root, &layers)?;
+        config.database = Some(config.database_path(db_root));
+        let path = build_library_config_path(db_root);
+        let table = toml::Table::try_from(&config)
+            .map_err(|e| PhotoDBError::new(format!("printing config: {}", e).as_str(), &path))?;
+        let mut values = Vec::new();
+        flatten("", &table, &mut values);
+        Ok(values
+            .into_iter()
+            .map(|(key, value)| {
+                let source = layers
+                    .iter()
+                    .rev()
+                    .find(|(_, layer)| contains(layer, &key))
+                    .map_or(Source::Default, |(source, _)| *source);
+                Setting { key, value: value.to_string(), source }
+            })
+            .collect())
+    }
+}
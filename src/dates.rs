@@ -0,0 +1,488 @@
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc,
+};
+use regex::Regex;
+use rexiv2::Metadata;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::metadata::{MetadataSource, Sourced};
+
+/// Date tags with the sub-second and offset tags that belong to them.
+const EXIF_DATE_KEYS: [(&str, &str, &str); 3] = [
+    (
+        "Exif.Photo.DateTimeOriginal",
+        "Exif.Photo.SubSecTimeOriginal",
+        "Exif.Photo.OffsetTimeOriginal",
+    ),
+    (
+        "Exif.Photo.DateTimeDigitized",
+        "Exif.Photo.SubSecTimeDigitized",
+        "Exif.Photo.OffsetTimeDigitized",
+    ),
+    ("Exif.Image.DateTime", "Exif.Photo.SubSecTime", "Exif.Photo.OffsetTime"),
+];
+
+// A-A-ATA:A:A
+// A-A-ATA:A:A.A
+// A-A-ATA:A:AZ
+// A:A:A
+// A:A:A A:A
+// A:A:A A:A:A
+const EXIF_DATE_F_STR: [&str; 7] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y:%m:%d %H:%M:%S",
+    "%Y:%m:%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%SZ",
+    "%Y:%m:%d %H:%M",
+];
+
+// A-A-A
+// A:A:A
+const EXIF_DAY_F_STR: [&str; 2] = ["%Y-%m-%d", "%Y:%m:%d"];
+
+const XMP_DATE_KEYS: [&str; 4] = [
+    "Xmp.exif.DateTimeOriginal",
+    "Xmp.photoshop.DateCreated",
+    "Xmp.xmp.CreateDate",
+    "Xmp.exif.DateTimeDigitized",
+];
+
+/// How many parent folders are searched for a date.
+const FOLDER_DEPTH: usize = 3;
+
+/// Offsets inferred from GPS time are rounded to the nearest quarter hour.
+const OFFSET_ROUNDING_SECS: i64 = 15 * 60;
+const MAX_OFFSET_SECS: i64 = 14 * 3600;
+
+/// Looks up the value of a tag by its exiv2 key, e.g. `Exif.Photo.DateTimeOriginal`.
+pub type Tags<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// The tags of a file read by exiv2.
+fn exif_tags(exif: &Metadata) -> impl Fn(&str) -> Option<String> + '_ {
+    move |key| exif.get_tag_string(key).ok()
+}
+
+/// When a photo was taken: the camera's wall clock time, and its offset from UTC when known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureTime {
+    pub local: NaiveDateTime,
+    pub offset: Option<FixedOffset>,
+}
+
+impl CaptureTime {
+    pub fn new(local: NaiveDateTime, offset: Option<FixedOffset>) -> Self {
+        CaptureTime { local, offset }
+    }
+
+    /// The capture instant. Without an offset the wall time is treated as UTC, which is how
+    /// dates were stored before offsets were recorded.
+    pub fn utc(&self) -> DateTime<Utc> {
+        let offset = self.offset.map(|o| o.local_minus_utc()).unwrap_or(0);
+        DateTime::from_naive_utc_and_offset(self.local - Duration::seconds(offset as i64), Utc)
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.utc().timestamp()
+    }
+
+    pub fn utc_offset(&self) -> Option<i32> {
+        self.offset.map(|o| o.local_minus_utc())
+    }
+
+    pub fn subsec_ms(&self) -> Option<i32> {
+        Some((self.local.nanosecond() / 1_000_000) as i32).filter(|ms| *ms > 0)
+    }
+
+    /// Rebuilds a capture time from the columns stored in the `photos` table.
+    pub fn from_db(exif_date: i64, utc_offset: Option<i32>, subsec_ms: Option<i32>) -> Self {
+        let offset = utc_offset.and_then(FixedOffset::east_opt);
+        let utc = DateTime::from_timestamp(exif_date, 0).unwrap_or_default().naive_utc()
+            + Duration::milliseconds(subsec_ms.unwrap_or(0) as i64);
+        let local =
+            utc + Duration::seconds(offset.map(|o| o.local_minus_utc()).unwrap_or(0) as i64);
+        CaptureTime { local, offset }
+    }
+}
+
+/// Finds the capture date of a photo, trying each source from the most to the least reliable:
+/// EXIF, XMP (embedded, then sidecar), GPS, libraw, the file name, parent folders and the file
+/// modification time.
+pub fn find_date(
+    exif: Option<&Metadata>, libraw: Option<CaptureTime>, og_path: &Path,
+) -> Option<Sourced<CaptureTime>> {
+    let tags = exif.map(exif_tags);
+    find_date_in(tags.as_ref().map(|tags| tags as Tags), libraw, og_path)
+}
+
+fn find_date_in(
+    tags: Option<Tags>, libraw: Option<CaptureTime>, og_path: &Path,
+) -> Option<Sourced<CaptureTime>> {
+    let sourced = |source| move |value| Sourced { value, source };
+    tags.and_then(get_date)
+        .map(sourced(MetadataSource::Exif))
+        .or_else(|| {
+            tags.and_then(get_xmp_date)
+                .or_else(|| get_sidecar_date(og_path))
+                .map(sourced(MetadataSource::Xmp))
+        })
+        .or_else(|| tags.and_then(get_gps_date).map(sourced(MetadataSource::Gps)))
+        .or_else(|| libraw.map(sourced(MetadataSource::Libraw)))
+        .or_else(|| get_filename_date(og_path).map(sourced(MetadataSource::Filename)))
+        .or_else(|| get_folder_date(og_path).map(sourced(MetadataSource::Folder)))
+        .or_else(|| get_mtime_date(og_path).map(sourced(MetadataSource::Mtime)))
+}
+
+/// Reads the EXIF capture date with its sub-seconds and offset. When the camera did not write
+/// an offset, it is inferred from the GPS time if there is one.
+pub fn get_date(tags: Tags) -> Option<CaptureTime> {
+    EXIF_DATE_KEYS.iter().find_map(|(date_key, subsec_key, offset_key)| {
+        let date = parse_date(&tags(date_key)?)?;
+        let local = match tags(subsec_key).and_then(|s| parse_subsec(&s)) {
+            Some(subsec) => date.local.with_nanosecond(subsec).unwrap_or(date.local),
+            None => date.local,
+        };
+        let offset = tags(offset_key)
+            .and_then(|o| o.trim().parse::<FixedOffset>().ok())
+            .or(date.offset)
+            .or_else(|| get_gps_date(tags).and_then(|gps| infer_offset(local, gps.local)));
+        Some(CaptureTime::new(local, offset))
+    })
+}
+
+/// Parses the date formats found in EXIF and XMP tags. Only RFC 3339 dates carry an offset.
+pub fn parse_date(date: &str) -> Option<CaptureTime> {
+    let date = date.trim();
+    EXIF_DATE_F_STR
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(date, f).ok())
+        .or_else(|| {
+            EXIF_DAY_F_STR
+                .iter()
+                .find_map(|f| NaiveDate::parse_from_str(date, f).ok())
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+        })
+        .map(|local| CaptureTime::new(local, None))
+        .or_else(|| {
+            DateTime::parse_from_rfc3339(date)
+                .ok()
+                .map(|d| CaptureTime::new(d.naive_local(), Some(*d.offset())))
+        })
+}
+
+/// `SubSecTime` holds the fractional digits, so `"5"` is 500ms and `"05"` is 50ms.
+fn parse_subsec(subsec: &str) -> Option<u32> {
+    let digits: String = subsec.trim().chars().take(9).collect();
+    let value: u32 = digits.parse().ok()?;
+    Some(value * 10u32.pow(9 - digits.len() as u32))
+}
+
+/// The offset between the camera clock and GPS time, if it looks like a real time zone.
+fn infer_offset(local: NaiveDateTime, utc: NaiveDateTime) -> Option<FixedOffset> {
+    let diff = (local - utc).num_seconds();
+    let rounded =
+        ((diff as f64 / OFFSET_ROUNDING_SECS as f64).round() as i64) * OFFSET_ROUNDING_SECS;
+    (rounded.abs() <= MAX_OFFSET_SECS).then(|| FixedOffset::east_opt(rounded as i32)).flatten()
+}
+
+pub fn get_xmp_date(tags: Tags) -> Option<CaptureTime> {
+    XMP_DATE_KEYS.iter().find_map(|key| tags(key).and_then(|date| parse_date(&date)))
+}
+
+/// Sidecars are named either `IMG_0001.xmp` or `IMG_0001.CR2.xmp`.
+pub fn find_sidecar(og_path: &Path) -> Option<PathBuf> {
+    let mut with_ext = og_path.as_os_str().to_owned();
+    with_ext.push(".xmp");
+    [og_path.with_extension("xmp"), og_path.with_extension("XMP"), PathBuf::from(with_ext)]
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+fn get_sidecar_date(og_path: &Path) -> Option<CaptureTime> {
+    find_sidecar(og_path)
+        .and_then(|sidecar| Metadata::new_from_path(sidecar).ok())
+        .and_then(|xmp| get_xmp_date(&exif_tags(&xmp)))
+}
+
+/// GPS time is always UTC, e.g. `2023:01:01` and `12/1 34/1 5600/100`.
+pub fn get_gps_date(tags: Tags) -> Option<CaptureTime> {
+    let day = tags("Exif.GPSInfo.GPSDateStamp")
+        .and_then(|d| NaiveDate::parse_from_str(d.trim(), "%Y:%m:%d").ok())?;
+    let time = tags("Exif.GPSInfo.GPSTimeStamp")?;
+    let parts: Vec<f64> = time
+        .split_whitespace()
+        .filter_map(|part| match part.split_once('/') {
+            Some((num, den)) => Some(num.parse::<f64>().ok()? / den.parse::<f64>().ok()?),
+            None => part.parse().ok(),
+        })
+        .collect();
+    match parts[..] {
+        [h, m, s] => NaiveTime::from_hms_opt(h as u32, m as u32, s as u32)
+            .map(|t| CaptureTime::new(day.and_time(t), FixedOffset::east_opt(0))),
+        _ => None,
+    }
+}
+
+/// Matches names like `IMG_20230101_123456`, `PXL_20230101_123456789` or `20230101-123456`.
+fn datetime_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(concat!(
+            r"(?:^|\D)((?:19|20)\d{2})[-_]?(\d{2})[-_]?(\d{2})",
+            r"[-_ T]?(\d{2})[-_.]?(\d{2})[-_.]?(\d{2})"
+        ))
+        .unwrap()
+    })
+}
+
+/// Matches `2023-01-01`, `2023_01_01` or `20230101` anywhere in a name.
+fn day_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"(?:^|\D)((?:19|20)\d{2})[-_.]?(\d{2})[-_.]?(\d{2})(?:\D|$)").unwrap()
+    })
+}
+
+fn capture_date(captures: &regex::Captures) -> Option<NaiveDate> {
+    let number = |i: usize| captures.get(i).and_then(|m| m.as_str().parse::<u32>().ok());
+    NaiveDate::from_ymd_opt(number(1)? as i32, number(2)?, number(3)?)
+}
+
+fn parse_name_date(name: &str) -> Option<CaptureTime> {
+    datetime_pattern()
+        .captures(name)
+        .and_then(|c| {
+            let number = |i: usize| c.get(i).and_then(|m| m.as_str().parse::<u32>().ok());
+            capture_date(&c)?.and_hms_opt(number(4)?, number(5)?, number(6)?)
+        })
+        .or_else(|| {
+            day_pattern().captures(name).and_then(|c| capture_date(&c)?.and_hms_opt(0, 0, 0))
+        })
+        .map(|local| CaptureTime::new(local, None))
+}
+
+pub fn get_filename_date(og_path: &Path) -> Option<CaptureTime> {
+    og_path.file_stem().and_then(OsStr::to_str).and_then(parse_name_date)
+}
+
+/// Looks for a dated folder such as `2023-01-01 Trip`, or a `2023/01` year and month pair,
+/// in the closest parent folders.
+pub fn get_folder_date(og_path: &Path) -> Option<CaptureTime> {
+    let names: Vec<&str> = og_path
+        .ancestors()
+        .skip(1)
+        .take(FOLDER_DEPTH + 1)
+        .filter_map(|p| p.file_name().and_then(OsStr::to_str))
+        .collect();
+    names.iter().take(FOLDER_DEPTH).find_map(|name| parse_name_date(name)).or_else(|| {
+        names.windows(2).find_map(|pair| {
+            let month: u32 = pair[0].parse().ok().filter(|_| pair[0].len() <= 2)?;
+            let year: i32 = pair[1].parse().ok().filter(|_| pair[1].len() == 4)?;
+            NaiveDate::from_ymd_opt(year, month, 1)
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+                .map(|local| CaptureTime::new(local, None))
+        })
+    })
+}
+
+/// The modification time is an instant, so it is placed in the local time zone of this machine.
+pub fn get_mtime_date(og_path: &Path) -> Option<CaptureTime> {
+    fs::metadata(og_path).and_then(|m| m.modified()).ok().map(|mtime| {
+        let local = DateTime::<Local>::from(mtime);
+        CaptureTime::new(local.naive_local(), Some(*local.offset()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::metadata::WEAK_DATE_SOURCES;
+    use crate::models::Photo;
+    use std::collections::HashMap;
+
+    fn time(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f").unwrap()
+    }
+
+    fn tags<'a>(pairs: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let tags: HashMap<&str, &str> = pairs.iter().copied().collect();
+        move |key| tags.get(key).map(|value| value.to_string())
+    }
+
+    fn hours(hours: i32) -> Option<FixedOffset> {
+        FixedOffset::east_opt(hours * 3600)
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("photodb-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn find(tags: Option<Tags>, libraw: Option<CaptureTime>, path: &str) -> Sourced<CaptureTime> {
+        find_date_in(tags, libraw, Path::new(path)).unwrap()
+    }
+
+    #[test]
+    fn ranks_fallback_dates() {
+        let named = "/card/2021-05-05 Trip/IMG_20230101_123456.ARW";
+        let libraw = CaptureTime::new(time("2022-02-02 02:02:02"), None);
+        let found = find(None, Some(libraw), named);
+        assert_eq!((found.source, found.value), (MetadataSource::Libraw, libraw));
+        let found = find(None, None, named);
+        assert_eq!(found.source, MetadataSource::Filename);
+        assert_eq!(found.value.local, time("2023-01-01 12:34:56"));
+        let found = find(None, None, "/card/2021-05-05 Trip/DSC0001.ARW");
+        assert_eq!(
+            (found.source, found.value.local),
+            (MetadataSource::Folder, time("2021-05-05 00:00:00"))
+        );
+        let found = find(None, None, "/card/2019/07/DSC0001.ARW");
+        assert_eq!(
+            (found.source, found.value.local),
+            (MetadataSource::Folder, time("2019-07-01 00:00:00"))
+        );
+        assert!(find_date_in(None, None, Path::new("/card/DSC0001.ARW")).is_none());
+    }
+
+    #[test]
+    fn falls_back_to_the_mtime() {
+        let dir = scratch_dir("dates-mtime");
+        let path = dir.join("DSC0001.ARW");
+        fs::write(&path, b"raw").unwrap();
+        let mtime = DateTime::<Local>::from(fs::metadata(&path).unwrap().modified().unwrap());
+
+        let found = find_date_in(None, None, &path).unwrap();
+
+        assert_eq!(found.source, MetadataSource::Mtime);
+        assert_eq!(found.value.local, mtime.naive_local());
+        assert_eq!(found.value.offset, Some(*mtime.offset()));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn prefers_exif_over_names() {
+        let named = "/card/2021-05-05/IMG_20230101_123456.ARW";
+        let exif = tags(&[("Exif.Image.DateTime", "2024:06:05 07:08:09")]);
+        let found = find(Some(&exif), None, named);
+        assert_eq!(
+            (found.source, found.value.local),
+            (MetadataSource::Exif, time("2024-06-05 07:08:09"))
+        );
+        let original = [
+            ("Exif.Image.DateTime", "2024:06:05 07:08:09"),
+            ("Exif.Photo.DateTimeOriginal", "2024:06:04 01:02:03"),
+        ];
+        let exif = tags(&original);
+        assert_eq!(find(Some(&exif), None, named).value.local, time("2024-06-04 01:02:03"));
+        let xmp = tags(&[("Xmp.xmp.CreateDate", "2024-06-03T10:00:00")]);
+        let found = find(Some(&xmp), None, named);
+        assert_eq!(
+            (found.source, found.value.local),
+            (MetadataSource::Xmp, time("2024-06-03 10:00:00"))
+        );
+        let gps = [
+            ("Exif.GPSInfo.GPSDateStamp", "2024:06:02"),
+            ("Exif.GPSInfo.GPSTimeStamp", "9/1 0/1 0/1"),
+        ];
+        let gps = tags(&gps);
+        let libraw = CaptureTime::new(time("2022-02-02 02:02:02"), None);
+        let found = find(Some(&gps), Some(libraw), named);
+        assert_eq!(
+            (found.source, found.value.local),
+            (MetadataSource::Gps, time("2024-06-02 09:00:00"))
+        );
+        let empty = tags(&[]);
+        assert_eq!(find(Some(&empty), None, named).source, MetadataSource::Filename);
+    }
+
+    #[test]
+    fn reads_subseconds() {
+        let exif = [
+            ("Exif.Photo.DateTimeOriginal", "2024:06:05 07:08:09"),
+            ("Exif.Photo.SubSecTimeOriginal", "05"),
+        ];
+        let date = get_date(&tags(&exif)).unwrap();
+        assert_eq!(date.local, time("2024-06-05 07:08:09.050"));
+        assert_eq!(date.subsec_ms(), Some(50));
+    }
+
+    #[test]
+    fn reads_offset_time() {
+        let exif = [
+            ("Exif.Photo.DateTimeOriginal", "2024:06:05 12:00:00"),
+            ("Exif.Photo.OffsetTimeOriginal", "+02:00"),
+            ("Exif.GPSInfo.GPSDateStamp", "2024:06:05"),
+            ("Exif.GPSInfo.GPSTimeStamp", "7/1 0/1 0/1"),
+        ];
+        let date = get_date(&tags(&exif)).unwrap();
+        assert_eq!((date.local, date.offset), (time("2024-06-05 12:00:00"), hours(2)));
+        assert_eq!(date.utc().naive_utc(), time("2024-06-05 10:00:00"));
+        let rfc3339 = parse_date("2024-06-05T12:00:00-05:30").unwrap();
+        assert_eq!(rfc3339.offset, FixedOffset::east_opt(-(5 * 3600 + 1800)));
+    }
+
+    #[test]
+    fn infers_offsets_from_gps_time() {
+        let exif = [
+            ("Exif.Photo.DateTimeOriginal", "2024:06:05 12:00:00"),
+            ("Exif.GPSInfo.GPSDateStamp", "2024:06:05"),
+            ("Exif.GPSInfo.GPSTimeStamp", "10/1 1/1 3050/100"),
+        ];
+        let date = get_date(&tags(&exif)).unwrap();
+        assert_eq!(date.offset, hours(2), "rounded to the nearest quarter hour");
+        let exif = [
+            ("Exif.Photo.DateTimeOriginal", "2024:06:05 23:00:00"),
+            ("Exif.GPSInfo.GPSDateStamp", "2024:06:04"),
+            ("Exif.GPSInfo.GPSTimeStamp", "20/1 0/1 0/1"),
+        ];
+        assert_eq!(get_date(&tags(&exif)).unwrap().offset, None, "more than 14 hours off");
+        let exif = [("Exif.Photo.DateTimeOriginal", "2024:06:05 12:00:00")];
+        assert_eq!(get_date(&tags(&exif)).unwrap().offset, None);
+    }
+
+    #[test]
+    fn reports_only_guessed_dates_as_weak() {
+        let dir = scratch_dir("dates-weak");
+        fs::create_dir_all(dir.join(".photodb")).unwrap();
+        let pool = db::get_connection_pool_for(&db::build_config_path(&dir)).unwrap();
+        let sources = ["exif", "xmp", "gps", "libraw", "filename", "folder", "mtime"];
+        for (i, source) in sources.iter().enumerate() {
+            let photo = Photo {
+                hash: i as i64,
+                original_path: format!("/card/{}.ARW", source),
+                current_path: dir.join(format!("{}.ARW", source)).to_string_lossy().to_string(),
+                exif_json: "{}".to_string(),
+                exif_date: 0,
+                camera_make: None,
+                camera_model: None,
+                lens: None,
+                serial: None,
+                iso: None,
+                shutter: None,
+                aperture: None,
+                focal_length: None,
+                metadata_sources: "{}".to_string(),
+                date_source: Some(source.to_string()),
+                utc_offset: None,
+                subsec_ms: None,
+                link_type: "copy".to_string(),
+            };
+            db::insert_file_to_db(&photo, &pool).unwrap();
+        }
+
+        let weak: Vec<&str> = WEAK_DATE_SOURCES.iter().map(|s| s.as_str()).collect();
+        let found = db::get_photos_by_date_source(&weak, &pool).unwrap();
+
+        let found: Vec<_> = found.iter().filter_map(|photo| photo.date_source.as_deref()).collect();
+        assert_eq!(found, vec!["filename", "folder", "mtime"]);
+        assert!(!MetadataSource::Libraw.is_weak());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,43 +1,189 @@
 use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use diesel::r2d2::Pool;
-use dotenvy::dotenv;
-use std::env;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
-use crate::models::Photo;
+use crate::models::{Photo, ScanEntry};
+use crate::photodb_error::PhotoDBError;
 
-pub fn get_connection_pool() -> Pool<ConnectionManager<SqliteConnection>> {
-    dotenv().ok();
-    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+pub fn build_config_path(db_root: &Path) -> PathBuf {
+    db_root.join(".photodb").join("photo.db")
+}
+
+/// Opens the database at `database`, creating it and running its migrations when needed.
+pub fn get_connection_pool_for(
+    database: &Path,
+) -> Result<Pool<ConnectionManager<SqliteConnection>>, PhotoDBError> {
+    let error = |msg: String| PhotoDBError::new(msg.as_str(), database);
+    let url = database.to_str().ok_or_else(|| error("path is not valid utf-8".to_string()))?;
+    build_pool(url).map_err(|e| error(format!("opening database: {}", e)))
+}
+
+fn build_pool(
+    url: &str,
+) -> Result<Pool<ConnectionManager<SqliteConnection>>, Box<dyn std::error::Error + Send + Sync>> {
     let manager = ConnectionManager::<SqliteConnection>::new(url);
     // Refer to the `r2d2` documentation for more methods to use
     // when building a connection pool
-    Pool::builder().test_on_check_out(true).build(manager).expect("Could not build connection pool")
+    let pool = Pool::builder().test_on_check_out(true).build(manager)?;
+    pool.get()?.run_pending_migrations(MIGRATIONS)?;
+    Ok(pool)
 }
 
-pub fn is_imported(hash: i64, pool: &Pool<ConnectionManager<SqliteConnection>>) -> bool {
+pub fn is_imported(
+    hash: i64, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<bool, PhotoDBError> {
     use crate::schema::photos;
-    let mut conn = pool.get().unwrap();
-    let results = photos::table
-        .filter(photos::hash.eq(hash))
-        .limit(1)
-        .load::<Photo>(&mut *conn)
-        .expect("Error loading photos");
-    !results.is_empty()
+    let mut conn = pool.get()?;
+    let results = photos::table.filter(photos::hash.eq(hash)).limit(1).load::<Photo>(&mut *conn)?;
+    Ok(!results.is_empty())
 }
 
 pub fn insert_file_to_db(
     photo: &Photo, pool: &Pool<ConnectionManager<SqliteConnection>>,
-) -> Result<usize, diesel::result::Error> {
+) -> Result<usize, PhotoDBError> {
+    use crate::schema::photos::dsl::*;
+    let mut conn = pool.get()?;
+    Ok(diesel::insert_into(photos).values(photo).execute(&mut *conn)?)
+}
+
+pub fn get_photos(
+    pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<HashSet<Photo>, PhotoDBError> {
     use crate::schema::photos::dsl::*;
-    let mut conn = pool.get().unwrap();
-    diesel::insert_into(photos).values(photo).execute(&mut *conn)
+    let mut conn = pool.get()?;
+    Ok(photos.load::<Photo>(&mut *conn)?.into_iter().collect())
 }
 
-pub fn get_photos(pool: &Pool<ConnectionManager<SqliteConnection>>) -> HashSet<Photo> {
+/// Photos whose capture date was read from one of `sources`.
+pub fn get_photos_by_date_source(
+    sources: &[&str], pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<Vec<Photo>, PhotoDBError> {
     use crate::schema::photos::dsl::*;
-    let mut conn = pool.get().unwrap();
-    photos.load::<Photo>(&mut *conn).expect("Error loading photos").into_iter().collect()
+    let mut conn = pool.get()?;
+    Ok(photos
+        .filter(date_source.eq_any(sources))
+        .order(current_path.asc())
+        .load::<Photo>(&mut *conn)?)
+}
+
+/// Photos taken with a camera, optionally narrowed to a model and a body serial number.
+pub fn get_photos_by_camera(
+    model: Option<&str>, body_serial: Option<&str>,
+    pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<Vec<Photo>, PhotoDBError> {
+    use crate::schema::photos::dsl::*;
+    let mut conn = pool.get()?;
+    let mut query = photos.into_boxed();
+    if let Some(model) = model {
+        query = query.filter(camera_model.eq(model));
+    }
+    if let Some(body_serial) = body_serial {
+        query = query.filter(serial.eq(body_serial));
+    }
+    Ok(query.order(exif_date.asc()).load::<Photo>(&mut *conn)?)
+}
+
+/// The photo stored at `path` in the library, or imported from `path`.
+pub fn get_photo_by_path(
+    path: &Path, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<Option<Photo>, PhotoDBError> {
+    use crate::schema::photos::dsl::*;
+    let mut conn = pool.get()?;
+    let path = path.to_string_lossy().to_string();
+    Ok(photos
+        .filter(current_path.eq(&path).or(original_path.eq(&path)))
+        .first::<Photo>(&mut *conn)
+        .optional()?)
+}
+
+/// Photos imported from a file called `name`, or from the path `name`.
+pub fn get_photos_by_original_name(
+    name: &str, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<Vec<Photo>, PhotoDBError> {
+    use crate::schema::photos::dsl::*;
+    let mut conn = pool.get()?;
+    Ok(photos
+        .filter(original_path.eq(name).or(original_path.like(format!("%{}", name))))
+        .load::<Photo>(&mut *conn)?
+        .into_iter()
+        .filter(|photo| {
+            photo.original_path == name
+                || Path::new(&photo.original_path).file_name() == Some(OsStr::new(name))
+        })
+        .collect())
+}
+
+pub fn update_current_path(
+    photo_hash: i64, path: &Path, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<usize, PhotoDBError> {
+    use crate::schema::photos::dsl::*;
+    let mut conn = pool.get()?;
+    Ok(diesel::update(photos.filter(hash.eq(photo_hash)))
+        .set(current_path.eq(path.to_string_lossy().to_string()))
+        .execute(&mut *conn)?)
+}
+
+/// The scan cache entry of a source file, if it was hashed before.
+pub fn get_scan_entry(
+    path: &Path, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<Option<ScanEntry>, PhotoDBError> {
+    use crate::schema::scan_cache;
+    let mut conn = pool.get()?;
+    Ok(scan_cache::table
+        .find(path.to_string_lossy().to_string())
+        .first::<ScanEntry>(&mut *conn)
+        .optional()?)
+}
+
+/// Records the hashes of freshly hashed source files, replacing older entries for the same paths.
+pub fn upsert_scan_entries(
+    entries: &[ScanEntry], pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<usize, PhotoDBError> {
+    use crate::schema::scan_cache;
+    let mut conn = pool.get()?;
+    Ok(conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        entries.iter().try_fold(0, |count, entry| {
+            diesel::replace_into(scan_cache::table).values(entry).execute(conn).map(|n| count + n)
+        })
+    })?)
+}
+
+/// Photos imported from a path under `prefix`.
+pub fn get_photos_by_original_prefix(
+    prefix: &Path, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<Vec<Photo>, PhotoDBError> {
+    use crate::schema::photos::dsl::*;
+    let mut conn = pool.get()?;
+    Ok(photos
+        .filter(original_path.like(like_prefix(prefix)).escape('\\'))
+        .order(original_path.asc())
+        .load::<Photo>(&mut *conn)?
+        .into_iter()
+        // LIKE compares strings, so the rows are checked to be in the directory itself.
+        .filter(|photo| Path::new(&photo.original_path).starts_with(prefix))
+        .collect())
+}
+
+/// A LIKE pattern matching the paths inside the directory `prefix`, not the ones that merely
+/// start with the same characters, with `\`, `%` and `_` in it escaped.
+fn like_prefix(prefix: &Path) -> String {
+    let mut pattern = String::new();
+    for c in prefix.to_string_lossy().chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    if !pattern.ends_with(std::path::MAIN_SEPARATOR) {
+        pattern.push(std::path::MAIN_SEPARATOR);
+    }
+    pattern.push('%');
+    pattern
 }
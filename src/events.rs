@@ -0,0 +1,338 @@
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::photodb_error::PhotoDBError;
+use crate::transfer::LinkMode;
+
+/// Exit code of a run that could not start or had to stop, e.g. on a bad config or database.
+pub const EXIT_FATAL: i32 = 1;
+/// Exit code of a run where some files failed to read, hash, copy, insert or delete.
+pub const EXIT_PARTIAL: i32 = 3;
+/// Exit code of a verification that found mismatched, missing, untracked or drifted files.
+pub const EXIT_MISMATCH: i32 = 4;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static RUN: Mutex<Run> = Mutex::new(Run { report: None, summary: None, failures: Vec::new() });
+
+/// What a run has reported so far, kept for the summary and the report file.
+struct Run {
+    report: Option<PathBuf>,
+    summary: Option<Event>,
+    failures: Vec<Event>,
+}
+
+/// What kind of step an error happened in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// Reading a file or its metadata
+    Read,
+    /// Decoding or hashing a raw
+    Decode,
+    /// Copying or linking a file into a library
+    Copy,
+    /// Moving a file inside a library
+    Move,
+    /// Committing or updating a database row
+    Insert,
+    /// Deleting a source or a library file
+    Delete,
+    /// Preserving times, permissions or extended attributes
+    Metadata,
+    /// A bad argument, config file or database
+    Input,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Read => "read",
+            Category::Decode => "decode",
+            Category::Copy => "copy",
+            Category::Move => "move",
+            Category::Insert => "insert",
+            Category::Delete => "delete",
+            Category::Metadata => "metadata",
+            Category::Input => "input",
+        }
+    }
+}
+
+/// How the binaries report what they do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, see the README for the schema
+    Json,
+}
+
+/// Selects the output format for the rest of the run.
+pub fn set_output(format: OutputFormat) {
+    JSON.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Something a binary did or found. Serialized as one JSON object per line, tagged with `event`.
+/// Field names are part of the documented schema: add fields, never rename or remove them.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A source file was decoded and its pixels hashed.
+    Hashed { path: String, hash: i64 },
+    /// A source file was not imported because the library already holds its pixels. `cached` is
+    /// set when the hash came from the scan cache instead of decoding the file.
+    SkippedDuplicate { path: String, hash: i64, cached: bool },
+    /// A file was copied or linked into a library.
+    Copied { from: String, to: String, link: String, dry_run: bool },
+    /// A database row was committed.
+    Inserted { path: String, current_path: String, hash: i64, dry_run: bool },
+    /// A library file was moved.
+    Moved { from: String, to: String, dry_run: bool },
+    /// A photo's capture time was corrected, moving it from `from` to `to`.
+    Shifted { from: String, to: String, old_time: String, new_time: String, dry_run: bool },
+    /// A file or directory was deleted.
+    Deleted { path: String, dry_run: bool },
+    /// A library file still has the hash recorded in the database.
+    Verified { path: String, hash: i64 },
+    /// A library file no longer has the hash recorded in the database.
+    Mismatch { path: String, expected: i64, actual: i64 },
+    /// A library file recorded in the database does not exist.
+    Missing { path: String },
+    /// A file in the library root is not recorded in the database.
+    Untracked { path: String },
+    /// A copy's times, permissions or extended attributes differ from its source.
+    MetadataDrift { path: String, differences: Vec<String> },
+    /// A thumbnail was written for a library file.
+    Thumbnail { path: String, thumbnail: String },
+    /// A photo whose capture date was guessed.
+    WeakDate { path: String, capture_time: String, date_source: String },
+    /// Where a photo imported from `original_path` is stored.
+    Located { original_path: String, current_path: String },
+    /// An EXIF tag of a file.
+    Exif { path: String, tag: String, value: String },
+    /// An effective setting, `value` written as in a config file, and where it came from.
+    Setting { key: String, value: String, source: String },
+    /// Something failed. `path` is empty when the error is not about one file.
+    Error { path: String, message: String, category: Category },
+    /// Totals for the run, printed last. `failures` counts the errors of each category and the
+    /// verification findings (`mismatch`, `missing`, `untracked`, `metadata_drift`).
+    Summary {
+        command: String,
+        #[serde(serialize_with = "serialize_counts")]
+        counts: Vec<(&'static str, usize)>,
+        #[serde(serialize_with = "serialize_counts")]
+        failures: Vec<(&'static str, usize)>,
+    },
+}
+
+fn serialize_counts<S: Serializer>(
+    counts: &[(&'static str, usize)], serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(counts.iter().map(|(name, count)| (name, count)))
+}
+
+/// Formats a path for an event.
+pub fn path(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+impl Event {
+    pub fn error(category: Category, path: &Path, message: impl fmt::Display) -> Self {
+        Event::Error { path: self::path(path), message: message.to_string(), category }
+    }
+
+    pub fn failed(category: Category, e: &PhotoDBError) -> Self {
+        Event::error(category, e.path(), e.details())
+    }
+
+    pub fn copied(from: &Path, to: &Path, link: LinkMode, dry_run: bool) -> Self {
+        Event::Copied {
+            from: self::path(from),
+            to: self::path(to),
+            link: link.as_str().to_string(),
+            dry_run,
+        }
+    }
+
+    /// A row committed for the photo read from `path` and stored at `current_path`.
+    pub fn inserted(path: &Path, current_path: &Path, hash: i64, dry_run: bool) -> Self {
+        Event::Inserted {
+            path: self::path(path),
+            current_path: self::path(current_path),
+            hash,
+            dry_run,
+        }
+    }
+
+    /// The failure category an event counts towards, if it is a failure.
+    fn failure(&self) -> Option<&'static str> {
+        match self {
+            Event::Error { category, .. } => Some(category.as_str()),
+            Event::Mismatch { .. } => Some("mismatch"),
+            Event::Missing { .. } => Some("missing"),
+            Event::Untracked { .. } => Some("untracked"),
+            Event::MetadataDrift { .. } => Some("metadata_drift"),
+            _ => None,
+        }
+    }
+}
+
+fn mock(dry_run: bool) -> &'static str {
+    if dry_run {
+        "mock "
+    } else {
+        ""
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Hashed { path, hash } => write!(f, "hashed file: {} -> {}", path, hash),
+            Event::SkippedDuplicate { path, hash, .. } => {
+                write!(f, "skipped duplicate: {} -> {}", path, hash)
+            }
+            Event::Copied { from, to, link, dry_run } => {
+                let verb = if link == "copy" { "copied" } else { "linked" };
+                write!(f, "{}{} file: {} -> {}", mock(*dry_run), verb, from, to)
+            }
+            Event::Inserted { path, current_path, dry_run, .. } => {
+                write!(f, "{}inserted file: {} -> {}", mock(*dry_run), path, current_path)
+            }
+            Event::Moved { from, to, dry_run } => {
+                write!(f, "{}moved file: {} -> {}", mock(*dry_run), from, to)
+            }
+            Event::Shifted { from, to, old_time, new_time, dry_run } => {
+                write!(f, "{}shifting {} {} -> {} {}", mock(*dry_run), from, old_time, new_time, to)
+            }
+            Event::Deleted { path, dry_run } => write!(f, "{}deleted: {}", mock(*dry_run), path),
+            Event::Verified { path, hash } => write!(f, "Verified: {} -> {:#x}", path, hash),
+            Event::Mismatch { path, expected, actual } => write!(
+                f,
+                "Error: hash mismatch on {} -> {:#x} file != {:#x} db",
+                path, actual, expected
+            ),
+            Event::Missing { path } => write!(f, "Error: file not found {} -> ???", path),
+            Event::Untracked { path } => write!(f, "Error: file not found in database {}", path),
+            Event::MetadataDrift { path, differences } => {
+                write!(f, "Error: metadata differs {} -> {}", path, differences.join(", "))
+            }
+            Event::Thumbnail { path, thumbnail } => {
+                write!(f, "thumbnail: {} -> {}", path, thumbnail)
+            }
+            Event::WeakDate { path, capture_time, date_source } => {
+                write!(f, "{}\t{}\t{}", path, capture_time, date_source)
+            }
+            Event::Located { original_path, current_path } => {
+                write!(f, "{} -> {}", original_path, current_path)
+            }
+            Event::Exif { path, tag, value } => write!(f, "{}\t{} :: {}", path, tag, value),
+            Event::Setting { key, value, source } => write!(f, "{} = {}\t# {}", key, value, source),
+            Event::Error { path, message, .. } => write!(f, "Error: {} -> {}", path, message),
+            Event::Summary { command, counts, failures } => {
+                let join = |counts: &[(&str, usize)]| {
+                    let counts: Vec<String> =
+                        counts.iter().map(|(name, count)| format!("{} {}", name, count)).collect();
+                    counts.join(", ")
+                };
+                write!(f, "Summary of {}: {}", command, join(counts))?;
+                match failures.is_empty() {
+                    true => write!(f, "\nNo failures"),
+                    false => write!(f, "\nFailures: {}", join(failures)),
+                }
+            }
+        }
+    }
+}
+
+/// Prints an event as a line of text or JSON, remembering failures for the summary.
+pub fn emit(event: Event) {
+    if is_json() {
+        println!("{}", serde_json::to_string(&event).expect("events serialize"));
+    } else {
+        println!("{}", event);
+    }
+    if event.failure().is_some() {
+        RUN.lock().unwrap().failures.push(event);
+    }
+}
+
+/// Writes a JSON report of the run to `path` when it finishes.
+pub fn set_report(path: Option<PathBuf>) {
+    RUN.lock().unwrap().report = path;
+}
+
+fn failure_counts(failures: &[Event]) -> Vec<(&'static str, usize)> {
+    let mut counts: BTreeMap<&'static str, usize> = BTreeMap::new();
+    for category in failures.iter().filter_map(|event| event.failure()) {
+        *counts.entry(category).or_default() += 1;
+    }
+    counts.into_iter().collect()
+}
+
+/// Prints the totals of a command, followed by its failures by category.
+pub fn summary(command: &str, counts: Vec<(&'static str, usize)>) {
+    let failures = failure_counts(&RUN.lock().unwrap().failures);
+    let summary = Event::Summary { command: command.to_string(), counts, failures };
+    RUN.lock().unwrap().summary = Some(summary.clone());
+    emit(summary);
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    exit_code: i32,
+    summary: Option<&'a Event>,
+    failures: &'a [Event],
+}
+
+/// Ends the run: writes the report file, if one was asked for, and returns the exit code.
+pub fn finish() -> i32 {
+    let run = RUN.lock().unwrap();
+    let failures: Vec<&str> = run.failures.iter().filter_map(|event| event.failure()).collect();
+    let code = if failures
+        .iter()
+        .any(|f| matches!(*f, "mismatch" | "missing" | "untracked" | "metadata_drift"))
+    {
+        EXIT_MISMATCH
+    } else if failures.is_empty() {
+        0
+    } else {
+        EXIT_PARTIAL
+    };
+    write_report(&run, code);
+    code
+}
+
+fn write_report(run: &Run, code: i32) {
+    let Some(path) = run.report.as_ref() else { return };
+    let report = Report { exit_code: code, summary: run.summary.as_ref(), failures: &run.failures };
+    let json = serde_json::to_string_pretty(&report).expect("reports serialize");
+    if let Err(e) = fs::write(path, json) {
+        eprintln!("Error: {} -> writing report: {}", path.display(), e);
+    }
+}
+
+/// Reports an error that stops the run and exits with `EXIT_FATAL`.
+pub fn fatal(event: Event) -> ! {
+    emit(event);
+    write_report(&RUN.lock().unwrap(), EXIT_FATAL);
+    exit(EXIT_FATAL)
+}
+
+/// Prints a progress message. Messages are not events, so they are left out of JSON output.
+pub fn note(message: impl fmt::Display) {
+    if !is_json() {
+        println!("{}", message);
+    }
+}
@@ -0,0 +1,62 @@
+use glob::glob;
+use rayon::prelude::*;
+use rexiv2::Metadata;
+use std::path::{Path, PathBuf};
+
+use crate::events::{self, Category, Event};
+use crate::formats::Formats;
+
+fn exif_event(path: &Path, tag: &str, value: String) -> Event {
+    Event::Exif { path: events::path(path), tag: tag.to_string(), value }
+}
+
+fn read_exif(path: &PathBuf) -> Option<Metadata> {
+    Metadata::new_from_path(path)
+        .map_err(|e| {
+            events::emit(Event::error(Category::Read, path, format!("reading exif: {}", e)))
+        })
+        .ok()
+}
+
+fn print_exif(path: &PathBuf) {
+    let Some(exif) = read_exif(path) else { return };
+    for f in exif.get_exif_tags().unwrap_or_default().iter() {
+        let val = exif.get_tag_string(f).unwrap_or_default();
+        if val.len() > 100 {
+            events::emit(exif_event(path, f, "<long value skipped>".to_string()));
+        } else {
+            events::emit(exif_event(path, f, val));
+        }
+    }
+}
+
+fn print_dates(path: &PathBuf) {
+    let Some(exif) = read_exif(path) else { return };
+    for t in exif.get_exif_tags().unwrap_or_default().iter().filter(|t| t.contains("Date")) {
+        let val = exif.get_tag_string(t).unwrap_or_default();
+        events::emit(exif_event(path, t, val));
+    }
+}
+
+/// Prints the exif tags, or only the date tags with `date_only`, of an image or of every image
+/// of `formats` below a directory.
+pub fn print_exif_tags(path: &PathBuf, date_only: bool, formats: &Formats) {
+    let func = if date_only { print_dates } else { print_exif };
+    match path {
+        path if path.is_dir() => scan_dir(path, func, formats),
+        path if path.is_file() => func(path),
+        path => events::emit(Event::error(Category::Input, path, "not a file or directory")),
+    }
+}
+
+fn scan_dir(image_directory: &Path, func: fn(&PathBuf), formats: &Formats) {
+    let img_files: Vec<PathBuf> =
+        glob(image_directory.join("**/*").as_os_str().to_str().expect("join"))
+            .map(|paths| {
+                paths.filter_map(|p| p.ok()).filter(|p| formats.is_image_file(p)).collect()
+            })
+            .unwrap_or_default();
+    img_files.par_iter().for_each(|f| {
+        func(f);
+    });
+}
@@ -0,0 +1,147 @@
+use libraw_rs_vendor::libraw_cameraList;
+use std::collections::BTreeSet;
+use std::ffi::{CStr, OsStr};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::config::FormatConfig;
+
+/// Raw extensions written by each maker, keyed by the maker prefix used in libraw's camera list.
+const MAKER_EXTENSIONS: [(&str, &[&str]); 26] = [
+    ("Canon", &["crw", "cr2", "cr3"]),
+    ("Nikon", &["nef", "nrw"]),
+    ("Sony", &["arw", "srf", "sr2"]),
+    ("Fujifilm", &["raf"]),
+    ("Olympus", &["orf"]),
+    ("OM Digital", &["orf"]),
+    ("Panasonic", &["rw2", "raw"]),
+    ("Leica", &["rwl", "raw", "dng"]),
+    ("Pentax", &["pef", "dng"]),
+    ("Ricoh", &["dng"]),
+    ("Samsung", &["srw"]),
+    ("Hasselblad", &["3fr", "fff"]),
+    ("Imacon", &["fff"]),
+    ("Phase One", &["iiq"]),
+    ("Leaf", &["mos"]),
+    ("Mamiya", &["mef"]),
+    ("Kodak", &["kdc", "dcr", "k25"]),
+    ("Sigma", &["x3f"]),
+    ("Minolta", &["mrw"]),
+    ("Konica Minolta", &["mrw"]),
+    ("Epson", &["erf"]),
+    ("Sinar", &["sti"]),
+    ("Nokia", &["nrw"]),
+    ("GoPro", &["gpr"]),
+    ("Apple", &["dng"]),
+    ("Google", &["dng"]),
+];
+
+/// Extensions that are never raws, so a TIFF magic number in one of them is not sniffed.
+const NON_RAW_EXTENSIONS: [&str; 14] = [
+    "jpg", "jpeg", "png", "gif", "heic", "heif", "tif", "tiff", "xmp", "txt", "json", "mov", "mp4",
+    "db",
+];
+
+/// The raw extensions the linked libraw can decode, derived from its supported camera list.
+pub fn supported_extensions() -> &'static BTreeSet<String> {
+    static SUPPORTED: OnceLock<BTreeSet<String>> = OnceLock::new();
+    SUPPORTED.get_or_init(|| {
+        let cameras = libraw_cameras();
+        let mut extensions: BTreeSet<String> = MAKER_EXTENSIONS
+            .iter()
+            .filter(|(maker, _)| cameras.iter().any(|camera| camera.starts_with(maker)))
+            .flat_map(|(_, exts)| exts.iter().map(|ext| ext.to_string()))
+            .collect();
+        // DNG is decoded generically, whichever camera wrote it.
+        extensions.insert("dng".to_string());
+        extensions
+    })
+}
+
+fn libraw_cameras() -> Vec<String> {
+    let mut cameras = Vec::new();
+    unsafe {
+        let mut list = libraw_cameraList();
+        while !list.is_null() && !(*list).is_null() {
+            cameras.push(CStr::from_ptr(*list).to_string_lossy().to_string());
+            list = list.add(1);
+        }
+    }
+    cameras
+}
+
+/// Decides which files are imported, from libraw's supported extensions adjusted by the
+/// library's `[formats]` settings.
+#[derive(Clone, Debug)]
+pub struct Formats {
+    pub extensions: BTreeSet<String>,
+    pub sniff: bool,
+}
+
+impl Formats {
+    pub fn new(config: &FormatConfig) -> Self {
+        let mut extensions = supported_extensions().clone();
+        extensions.extend(config.include.iter().map(|ext| normalize(ext)));
+        for ext in config.exclude.iter() {
+            extensions.remove(&normalize(ext));
+        }
+        Formats { extensions, sniff: config.sniff }
+    }
+
+    pub fn is_image_file(&self, path: &Path) -> bool {
+        if is_hidden(path) || !path.is_file() {
+            return false;
+        }
+        match path.extension().and_then(OsStr::to_str).map(normalize) {
+            Some(ext) if self.extensions.contains(&ext) => true,
+            Some(ext) if NON_RAW_EXTENSIONS.contains(&ext.as_str()) => false,
+            _ => self.sniff && sniff_raw(path),
+        }
+    }
+
+    /// Whether an archive entry is named like a raw. Entries are not sniffed, since that would
+    /// mean reading every entry of the archive.
+    pub fn is_image_name(&self, path: &Path) -> bool {
+        let ext = path.extension().and_then(OsStr::to_str).map(normalize);
+        !is_hidden(path) && ext.is_some_and(|ext| self.extensions.contains(&ext))
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().and_then(OsStr::to_str).map(|name| name.starts_with('.')).unwrap_or(true)
+}
+
+fn normalize(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
+}
+
+/// Checks the file header for the magic numbers used by raw containers.
+pub fn sniff_raw(path: &Path) -> bool {
+    let mut header = [0u8; 16];
+    let read = File::open(path).and_then(|mut f| f.read(&mut header)).unwrap_or(0);
+    read == header.len() && is_raw_header(&header)
+}
+
+fn is_raw_header(header: &[u8; 16]) -> bool {
+    match header {
+        // TIFF based: CR2, NEF, ARW, DNG, PEF, SRW, 3FR, ERF, KDC, DCR, IIQ, MOS, ...
+        [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => true,
+        // Olympus ORF
+        [b'I', b'I', b'R', b'O', ..]
+        | [b'I', b'I', b'R', b'S', ..]
+        | [b'M', b'M', b'O', b'R', ..] => true,
+        // Panasonic RW2 / Leica RWL
+        [b'I', b'I', b'U', 0x00, ..] => true,
+        // Canon CR3 (ISO base media with a "crx " brand)
+        [_, _, _, _, b'f', b't', b'y', b'p', b'c', b'r', b'x', b' ', ..] => true,
+        // Canon CRW
+        [b'I', b'I', 0x1a, 0x00, 0x00, 0x00, b'H', b'E', b'A', b'P', b'C', b'C', b'D', b'R', ..] => {
+            true
+        }
+        // Sigma X3F, Minolta MRW
+        [b'F', b'O', b'V', b'b', ..] | [0x00, b'M', b'R', b'M', ..] => true,
+        _ => header.starts_with(b"FUJIFILMCCD-RAW"),
+    }
+}
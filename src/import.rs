@@ -0,0 +1,982 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use glob::{glob_with, MatchOptions};
+use rayon::prelude::*;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use crate::archive;
+use crate::companions;
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::formats::Formats;
+use crate::import_filter::{IgnoreFiles, ImportFilter};
+use crate::import_journal::{self, ImportJournal, ImportOptions, PendingImport, Record};
+use crate::models::ScanEntry;
+use crate::path_template::PathTemplate;
+use crate::photodb_error::PhotoDBError;
+use crate::plan::Plan;
+use crate::raw_photo::Photo;
+use crate::scan_cache;
+use crate::thumbnail;
+use crate::transfer::{self, LinkMode};
+use crate::util;
+use crate::watch::{self, WatchOptions};
+
+#[allow(clippy::too_many_arguments)]
+pub fn import_directory(
+    options: &ImportOptions, filter: &ImportFilter, import_path: &Path, rescan: bool,
+    plan_path: Option<&PathBuf>, database: &Pool<ConnectionManager<SqliteConnection>>,
+    formats: &Formats, template: &PathTemplate,
+) {
+    let path_to_import = &options.source;
+    if !path_to_import.is_dir() {
+        events::fatal(Event::error(Category::Input, path_to_import, "not a directory"));
+    }
+    resume_unfinished(options, import_path, plan_path, database, template);
+    let img_files = get_img_file_list(path_to_import, formats, filter);
+    import_files(options, filter, img_files, import_path, rescan, plan_path, database, template)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+}
+
+/// Imports the raws of a zip or tar archive, reading it entry by entry: once to hash the raws,
+/// and once more to write the new ones into the library. Each is recorded with the original path
+/// `archive.zip!/inner/path.NEF`.
+pub fn import_archive(
+    options: &ImportOptions, filter: &ImportFilter, import_path: &Path,
+    database: &Pool<ConnectionManager<SqliteConnection>>, formats: &Formats,
+    template: &PathTemplate,
+) {
+    let archive = &options.source;
+    if options.delete_source {
+        let message = "--delete-source cannot delete files inside an archive";
+        events::fatal(Event::error(Category::Input, archive, message));
+    }
+    resume_unfinished(options, import_path, None, database, template);
+    let mut total_files = 0;
+    let mut photo_vec: Vec<Photo> = Vec::new();
+    let mut chunk: Vec<(PathBuf, Vec<u8>)> = Vec::new();
+    let mut hash_chunk = |chunk: &mut Vec<(PathBuf, Vec<u8>)>| {
+        let hashed = chunk.par_drain(..).filter_map(|(path, buf)| {
+            let photo = Photo::new(&buf, &path, import_path, template)
+                .map_err(|e| events::emit(Event::failed(Category::Decode, &e)))
+                .ok()?;
+            events::emit(Event::Hashed { path: events::path(&path), hash: photo.hash as i64 });
+            Some(photo)
+        });
+        photo_vec.par_extend(hashed);
+    };
+    let read = archive::for_each_entry(
+        archive,
+        |inner, size| formats.is_image_name(inner) && filter.accepts_entry(inner, size),
+        |inner, buf| {
+            total_files += 1;
+            chunk.push((archive::entry_path(archive, &inner), buf));
+            // Decode a few entries at a time, without holding the whole archive in memory.
+            if chunk.len() >= rayon::current_num_threads() {
+                hash_chunk(&mut chunk);
+            }
+        },
+    );
+    hash_chunk(&mut chunk);
+    if let Err(e) = read {
+        events::emit(Event::failed(Category::Read, &e));
+    }
+    events::note(format!("Hashed {}/{} archive entries", photo_vec.len(), total_files));
+    import_photos(
+        options,
+        filter,
+        photo_vec,
+        (total_files, 0),
+        import_path,
+        None,
+        database,
+        template,
+    )
+    .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+}
+
+/// Imports exactly the files listed on stdin. Paths that are missing or not a supported image are
+/// reported and skipped; the include, exclude and size filters still apply, ignore files do not.
+#[allow(clippy::too_many_arguments)]
+pub fn import_path_list(
+    options: &ImportOptions, filter: &ImportFilter, null: bool, import_path: &Path, rescan: bool,
+    plan_path: Option<&PathBuf>, database: &Pool<ConnectionManager<SqliteConnection>>,
+    formats: &Formats, template: &PathTemplate,
+) {
+    let mut input = Vec::new();
+    io::stdin()
+        .read_to_end(&mut input)
+        .unwrap_or_else(|e| events::fatal(Event::error(Category::Input, Path::new("-"), e)));
+    let separator = if null { b'\0' } else { b'\n' };
+    let img_files: Vec<PathBuf> = input
+        .split(|b| *b == separator)
+        .map(|line| String::from_utf8_lossy(line).trim_end_matches('\r').to_string())
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .filter(|path| {
+            if !path.is_file() {
+                events::emit(Event::error(Category::Read, path, "not a file"));
+                return false;
+            }
+            let relative = path.strip_prefix(&options.source).unwrap_or(path);
+            if !filter.accepts_file(path, relative) {
+                return false;
+            }
+            let image = formats.is_image_file(path);
+            if !image {
+                events::note(format!("skipped: {} is not a supported raw", path.display()));
+            }
+            image
+        })
+        .collect();
+    resume_unfinished(options, import_path, plan_path, database, template);
+    import_files(options, filter, img_files, import_path, rescan, plan_path, database, template)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+}
+
+/// Finishes earlier runs of an import from the same source before planning a new one.
+fn resume_unfinished(
+    options: &ImportOptions, import_path: &Path, plan_path: Option<&PathBuf>,
+    database: &Pool<ConnectionManager<SqliteConnection>>, template: &PathTemplate,
+) {
+    if !(options.copy_files || options.insert) || plan_path.is_some() {
+        return;
+    }
+    for id in import_journal::unfinished_ids(import_path) {
+        match import_journal::read_pending(import_path, &id) {
+            Ok(pending) if pending.options.source == options.source => {
+                resume_import(&id, import_path, database, template)
+            }
+            Ok(_) => {}
+            Err(e) => events::emit(Event::failed(Category::Read, &e)),
+        }
+    }
+}
+
+/// Hashes, dedupes and places `img_files`, then copies, inserts and deletes them as `options` asks.
+/// Errors that stop the whole import, like an unreadable database, are returned.
+#[allow(clippy::too_many_arguments)]
+fn import_files(
+    options: &ImportOptions, filter: &ImportFilter, mut img_files: Vec<PathBuf>,
+    import_path: &Path, rescan: bool, plan_path: Option<&PathBuf>,
+    database: &Pool<ConnectionManager<SqliteConnection>>, template: &PathTemplate,
+) -> Result<(), PhotoDBError> {
+    let total_files = img_files.len();
+    events::note(format!("Importing {} files", total_files));
+    if !rescan {
+        // Files whose size, mtime and inode are unchanged keep the hash they had last time.
+        let mut changed = Vec::with_capacity(img_files.len());
+        for path in img_files {
+            match scan_cache::cached_hash(&path, database)? {
+                Some(hash) if db::is_imported(hash, database)? => {
+                    let path = events::path(&path);
+                    events::emit(Event::SkippedDuplicate { path, hash, cached: true });
+                }
+                _ => changed.push(path),
+            }
+        }
+        img_files = changed;
+        events::note(format!(
+            "Skipped {} unchanged files already imported",
+            total_files - img_files.len()
+        ));
+    }
+    let unchanged = total_files - img_files.len();
+    // Files are stat'ed before they are hashed, so that one changed while it was read is not
+    // cached with the hash of its old contents.
+    let mut stats = match options.insert {
+        true => scan_cache::stat_before_hashing(&img_files),
+        false => HashMap::new(),
+    };
+    let photo_vec = get_photos_from_img_file_list(&img_files, import_path, template);
+    let hashed = photo_vec.len();
+    events::note(format!("Hashed {}/{} files", hashed, img_files.len()));
+    if options.insert {
+        let hashes: Vec<ScanEntry> = photo_vec
+            .iter()
+            .filter_map(|photo| {
+                let before = stats.remove(&photo.og_path)?;
+                Some(ScanEntry { hash: photo.hash as i64, ..before })
+            })
+            .collect();
+        scan_cache::record(hashes, database);
+    }
+    import_photos(
+        options,
+        filter,
+        photo_vec,
+        (total_files, unchanged),
+        import_path,
+        plan_path,
+        database,
+        template,
+    )
+}
+
+/// Filters, dedupes and places hashed photos, then copies, inserts and deletes them as `options`
+/// asks. `counts` are the number of files found and of those skipped as unchanged.
+#[allow(clippy::too_many_arguments)]
+fn import_photos(
+    options: &ImportOptions, filter: &ImportFilter, photo_vec: Vec<Photo>, counts: (usize, usize),
+    import_path: &Path, plan_path: Option<&PathBuf>,
+    database: &Pool<ConnectionManager<SqliteConnection>>, template: &PathTemplate,
+) -> Result<(), PhotoDBError> {
+    let journaled = (options.copy_files || options.insert) && plan_path.is_none();
+    let (total_files, unchanged) = counts;
+    let hashed = photo_vec.len();
+    let photo_vec: Vec<Photo> = match filter.filters_photos() {
+        true => {
+            photo_vec.into_iter().filter(|photo| filter.accepts_photo(&photo.to_db_row())).collect()
+        }
+        false => photo_vec,
+    };
+    let filtered = hashed - photo_vec.len();
+    if filtered > 0 {
+        events::note(format!("Filtered out {} photos by capture time or camera", filtered));
+    }
+    let mut new_photos: Vec<Photo> = Vec::with_capacity(photo_vec.len());
+    for photo in photo_vec {
+        if db::is_imported(photo.hash as i64, database)? {
+            events::emit(Event::SkippedDuplicate {
+                path: events::path(&photo.og_path),
+                hash: photo.hash as i64,
+                cached: false,
+            });
+        } else {
+            new_photos.push(photo);
+        }
+    }
+    let duplicates = hashed - filtered - new_photos.len();
+    place_photos(&mut new_photos, template);
+    for photo in new_photos.iter_mut() {
+        photo.link = LinkMode::from_db(&options.link);
+    }
+    events::note(format!("{}/{} files to copy", new_photos.len(), total_files));
+    if let Some(plan_path) = plan_path {
+        write_import_plan(&new_photos, options, import_path, plan_path);
+        return Ok(());
+    }
+    let journal = match journaled && !new_photos.is_empty() {
+        true => ImportJournal::create(import_path, options).and_then(|mut journal| {
+            for photo in new_photos.iter() {
+                journal.append(&Record::Planned {
+                    hash: photo.hash as i64,
+                    path: photo.og_path.clone(),
+                    dest: photo.db_path.clone(),
+                })?;
+            }
+            Ok(Some(Mutex::new(journal)))
+        })?,
+        false => None,
+    };
+    let planned = new_photos.len();
+    let progress = place_photos_in_library(new_photos, options, None, journal.as_ref(), database);
+    finish_journal(journal, progress.finished == planned);
+    events::summary(
+        "import",
+        vec![
+            ("files", total_files),
+            ("unchanged", unchanged),
+            ("hashed", hashed),
+            ("filtered", filtered),
+            ("duplicates", duplicates),
+            ("copied", progress.copied),
+            ("inserted", progress.inserted),
+            ("kept_sources", progress.kept),
+        ],
+    );
+    Ok(())
+}
+
+/// Writes the copies, rows and source deletions an import would make to a plan file.
+fn write_import_plan(
+    photos: &[Photo], options: &ImportOptions, import_path: &Path, plan_path: &PathBuf,
+) {
+    let mut plan = Plan::new("import", import_path, options.xattrs);
+    for photo in photos.iter() {
+        // A placement both copies the file and commits its row, like `--copy --insert`.
+        let placed = match options.copy_files && options.insert {
+            true => plan.place(&photo.og_path, photo.to_db_row()),
+            false => Ok(()),
+        };
+        let planned = placed.and_then(|_| match options.delete_source {
+            true => plan.delete_source(&photo.og_path, photo.hash as i64, &photo.db_path),
+            false => Ok(()),
+        });
+        planned.map_err(|e| events::emit(Event::failed(Category::Read, &e))).ok();
+    }
+    plan.write(plan_path).unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    events::note(format!(
+        "Wrote {} operations to {}, run `photodb apply {}` to run them",
+        plan.operations.len(),
+        plan_path.display(),
+        plan_path.display()
+    ));
+    events::summary("import", vec![("photos", photos.len()), ("planned", plan.operations.len())]);
+}
+
+/// How far the photos of an import got.
+struct Progress {
+    copied: usize,
+    inserted: usize,
+    kept: usize,
+    /// Photos that went through every step the import asked for.
+    finished: usize,
+}
+
+/// Copies the photos into the library, commits their rows and deletes their sources, as
+/// `options` asks. Each step is recorded in `journal`; the steps `pending` records as done are
+/// skipped, and so are rows committed by an interrupted run that did not get to record them.
+fn place_photos_in_library(
+    photos: Vec<Photo>, options: &ImportOptions, pending: Option<&PendingImport>,
+    journal: Option<&Mutex<ImportJournal>>, database: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Progress {
+    let record = |record: Record| {
+        if let Some(journal) = journal {
+            if let Err(e) = journal.lock().unwrap().append(&record) {
+                events::emit(Event::failed(Category::Insert, &e));
+            }
+        }
+    };
+    let total = photos.len();
+    let refused = Mutex::new(Vec::new());
+    let placed: Vec<(Photo, bool)> = if options.copy_files {
+        let _temp_lock = photos.first().and_then(|photo| transfer::lock_temp_files(&photo.db_root));
+        // Archive entries are written while reading through their archive.
+        let (entries, files): (Vec<Photo>, Vec<Photo>) =
+            photos.into_iter().partition(|photo| archive::is_entry_path(&photo.og_path));
+        let mut placed: Vec<(Photo, bool)> = files
+            .into_par_iter()
+            .filter_map(|mut photo| {
+                let hash = photo.hash as i64;
+                if let Some(link) = pending.and_then(|pending| pending.copied.get(&hash)) {
+                    photo.link = LinkMode::from_db(link);
+                    return Some((photo, false));
+                }
+                let placed = copy_photo(photo, options.delete_source, options.xattrs, &refused)?;
+                record(Record::Copied { hash, link: placed.0.link.as_str().to_string() });
+                Some(placed)
+            })
+            .collect();
+        let (copied, entries): (Vec<Photo>, Vec<Photo>) = entries.into_iter().partition(|photo| {
+            pending.is_some_and(|pending| pending.copied.contains_key(&(photo.hash as i64)))
+        });
+        placed.extend(copied.into_iter().map(|photo| (photo, false)));
+        for placed_entry in copy_archive_entries(entries) {
+            let photo = &placed_entry.0;
+            record(Record::Copied {
+                hash: photo.hash as i64,
+                link: photo.link.as_str().to_string(),
+            });
+            placed.push(placed_entry);
+        }
+        placed
+    } else {
+        photos
+            .into_iter()
+            .map(|photo| {
+                events::emit(Event::copied(&photo.og_path, &photo.db_path, photo.link, true));
+                (photo, true)
+            })
+            .collect()
+    };
+    let copied = placed.iter().filter(|(_, created)| *created).count();
+    events::note(format!("Copied {}/{} files", copied, total));
+    // Rows are only committed once their file is in place.
+    let inserted: Vec<Photo> = placed
+        .into_iter()
+        .filter_map(|(photo, created)| {
+            let hash = photo.hash as i64;
+            let done = match pending {
+                Some(pending) if pending.inserted.contains(&hash) => Ok(true),
+                Some(_) => db::is_imported(hash, database),
+                None => Ok(false),
+            };
+            match done {
+                Ok(true) => return Some(photo),
+                Ok(false) => {}
+                Err(e) => {
+                    events::emit(Event::error(Category::Insert, &photo.og_path, e.details()));
+                    return None;
+                }
+            }
+            if options.insert {
+                db::insert_file_to_db(&photo.to_db_row(), database)
+                    .map_err(|e| {
+                        events::emit(Event::error(
+                            Category::Insert,
+                            &photo.og_path,
+                            format!("inserting file: {}", e.details()),
+                        ));
+                        if options.copy_files && created {
+                            remove_placed(&photo);
+                        }
+                    })
+                    .ok()
+                    .map(|_| {
+                        events::emit(Event::inserted(
+                            &photo.og_path,
+                            &photo.db_path,
+                            photo.hash as i64,
+                            false,
+                        ));
+                        record(Record::Inserted { hash });
+                        photo
+                    })
+            } else {
+                events::emit(Event::inserted(
+                    &photo.og_path,
+                    &photo.db_path,
+                    photo.hash as i64,
+                    true,
+                ));
+                Some(photo)
+            }
+        })
+        .collect();
+    events::note(format!("Inserted {}/{} files", inserted.len(), total));
+    if options.copy_files && options.insert {
+        write_thumbnails(&inserted);
+    }
+    let finished = if options.delete_source {
+        inserted
+            .par_iter()
+            .filter(|photo| {
+                let hash = photo.hash as i64;
+                if pending.is_some_and(|pending| pending.deleted.contains(&hash)) {
+                    return true;
+                }
+                // An interrupted run may have deleted the source without recording it.
+                let deleted = (pending.is_some() && !photo.og_path.exists())
+                    || delete_sources(photo, &refused);
+                if deleted {
+                    record(Record::Deleted { hash });
+                }
+                deleted
+            })
+            .count()
+    } else {
+        inserted.len()
+    };
+    let refused = refused.into_inner().unwrap();
+    if !refused.is_empty() {
+        events::note(format!("Kept {} source files:", refused.len()));
+        for e in refused.iter() {
+            events::note(format!("\t{}", e));
+        }
+    }
+    Progress { copied, inserted: inserted.len(), kept: refused.len(), finished }
+}
+
+/// Caches the thumbnails of photos placed in the library and recorded in the database, reading
+/// each back from its library path.
+fn write_thumbnails(photos: &[Photo]) {
+    photos.par_iter().for_each(|photo| {
+        fs::read(&photo.db_path)
+            .map_err(|e| PhotoDBError::new(format!("reading file: {}", e).as_str(), &photo.db_path))
+            .and_then(|buf| {
+                thumbnail::write_thumbnail(&buf, &photo.db_path, &photo.db_root, photo.hash as i64)
+            })
+            .map(|thumb_path| {
+                events::emit(Event::Thumbnail {
+                    path: events::path(&photo.db_path),
+                    thumbnail: events::path(&thumb_path),
+                })
+            })
+            .map_err(|e| events::emit(Event::failed(Category::Decode, &e)))
+            .ok();
+    });
+}
+
+/// Removes the journal of an import whose photos are all done, or tells how to retry the rest.
+fn finish_journal(journal: Option<Mutex<ImportJournal>>, finished: bool) {
+    let Some(journal) = journal.map(|journal| journal.into_inner().unwrap()) else { return };
+    if finished {
+        journal.finish().map_err(|e| events::emit(Event::failed(Category::Delete, &e))).ok();
+    } else {
+        events::note(format!(
+            "Import {} is unfinished, run `photodb import --resume {}` to retry it",
+            journal.id, journal.id
+        ));
+    }
+}
+
+/// Finishes an interrupted import from its journal. Photos whose steps are not all recorded are
+/// decoded again, from the library copy if the source is gone, and taken through the rest.
+pub fn resume_import(
+    id: &str, import_path: &Path, database: &Pool<ConnectionManager<SqliteConnection>>,
+    template: &PathTemplate,
+) {
+    let pending = import_journal::read_pending(import_path, id)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let unfinished = pending.unfinished();
+    events::note(format!("Resuming import {} with {} photos left", id, unfinished.len()));
+    let photos: Vec<Photo> = unfinished
+        .par_iter()
+        .filter_map(|planned| {
+            let from_archive = archive::is_entry_path(&planned.path) && !planned.dest.exists();
+            let file =
+                if planned.path.exists() || from_archive { &planned.path } else { &planned.dest };
+            let buf = match from_archive {
+                true => archive::read_entry(file)
+                    .map_err(|e| events::emit(Event::failed(Category::Read, &e)))
+                    .ok()?,
+                false => fs::read(file)
+                    .map_err(|e| events::emit(Event::error(Category::Read, file, e)))
+                    .ok()?,
+            };
+            let mut photo = Photo::new(&buf, &planned.path, import_path, template)
+                .map_err(|e| events::emit(Event::failed(Category::Decode, &e)))
+                .ok()?;
+            if photo.hash as i64 != planned.hash {
+                let message = "changed since the import was planned";
+                events::emit(Event::error(Category::Read, file, message));
+                return None;
+            }
+            photo.db_path = planned.dest.clone();
+            photo.link = LinkMode::from_db(&pending.options.link);
+            Some(photo)
+        })
+        .collect();
+    let journal = ImportJournal::open(import_path, id)
+        .map(Mutex::new)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let progress =
+        place_photos_in_library(photos, &pending.options, Some(&pending), Some(&journal), database);
+    finish_journal(Some(journal), progress.finished == unfinished.len());
+    events::summary(
+        "resume",
+        vec![
+            ("planned", pending.planned.len()),
+            ("unfinished", unfinished.len()),
+            ("copied", progress.copied),
+            ("inserted", progress.inserted),
+            ("kept_sources", progress.kept),
+        ],
+    );
+}
+
+/// Copies or links a photo and its sidecars and paired JPEGs into the library, recording the link
+/// type that was used. Returns `None` if the photo could not be placed, adding the error to
+/// `refused` when its source was going to be deleted, and otherwise whether a file was created.
+fn copy_photo(
+    mut photo: Photo, delete_source: bool, xattrs: bool, refused: &Mutex<Vec<PhotoDBError>>,
+) -> Option<(Photo, bool)> {
+    //check if photo.db_path exists, create it if it does not
+    if !photo.db_path.parent().unwrap().exists() {
+        if let Err(e) = fs::create_dir_all(photo.db_path.parent().unwrap()) {
+            events::emit(Event::error(
+                Category::Copy,
+                &photo.og_path,
+                format!("creating directory: {}", e),
+            ));
+            return None;
+        }
+    }
+    let created = match transfer::link_or_copy(
+        &photo.og_path,
+        &photo.db_path,
+        photo.link,
+        Some(photo.hash as i64),
+        xattrs,
+    ) {
+        Ok(used) => {
+            events::emit(Event::copied(&photo.og_path, &photo.db_path, used, false));
+            photo.link = used;
+            true
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            events::note(format!(
+                "file already in library: {} -> {}",
+                &photo.og_path.display(),
+                &photo.db_path.display()
+            ));
+            false
+        }
+        Err(e) => {
+            let e = PhotoDBError::new(format!("copying file: {}", e).as_str(), &photo.og_path);
+            events::emit(Event::failed(Category::Copy, &e));
+            if delete_source {
+                refused.lock().unwrap().push(e);
+            }
+            return None;
+        }
+    };
+    for companion in companions::find_companions(&photo.og_path) {
+        let dest = companions::companion_destination(&companion, &photo.og_path, &photo.db_path);
+        match transfer::link_or_copy(&companion, &dest, photo.link, None, xattrs) {
+            Ok(used) => events::emit(Event::copied(&companion, &dest, used, false)),
+            Err(e) => events::emit(Event::error(
+                Category::Copy,
+                &companion,
+                format!("copying companion: {}", e),
+            )),
+        }
+    }
+    Some((photo, created))
+}
+
+/// Writes archive entries into the library, reading each archive once.
+fn copy_archive_entries(photos: Vec<Photo>) -> Vec<(Photo, bool)> {
+    let mut by_archive: HashMap<PathBuf, HashMap<PathBuf, Photo>> = HashMap::new();
+    for photo in photos {
+        if let Some((archive, inner)) = archive::split_entry_path(&photo.og_path) {
+            by_archive.entry(archive).or_default().insert(inner, photo);
+        }
+    }
+    let mut placed = Vec::new();
+    for (archive, mut photos) in by_archive {
+        let wanted: HashSet<PathBuf> = photos.keys().cloned().collect();
+        let read = archive::for_each_entry(
+            &archive,
+            |inner, _| wanted.contains(inner),
+            |inner, buf| {
+                let Some(mut photo) = photos.remove(&inner) else { return };
+                photo.link = LinkMode::Copy;
+                let written =
+                    photo.db_path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| {
+                        transfer::write_new_verified(&buf, &photo.db_path, photo.hash as i64)
+                    });
+                match written {
+                    Ok(_) => {
+                        events::emit(Event::copied(
+                            &photo.og_path,
+                            &photo.db_path,
+                            photo.link,
+                            false,
+                        ));
+                        placed.push((photo, true));
+                    }
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                        events::note(format!(
+                            "file already in library: {} -> {}",
+                            photo.og_path.display(),
+                            photo.db_path.display()
+                        ));
+                        placed.push((photo, false));
+                    }
+                    Err(e) => {
+                        let message = format!("copying file: {}", e);
+                        events::emit(Event::error(Category::Copy, &photo.og_path, message));
+                    }
+                }
+            },
+        );
+        if let Err(e) = read {
+            events::emit(Event::failed(Category::Read, &e));
+        }
+        for photo in photos.values() {
+            events::emit(Event::error(Category::Read, &photo.og_path, "entry not found"));
+        }
+    }
+    placed
+}
+
+/// Removes a photo placed in the library whose row could not be committed.
+fn remove_placed(photo: &Photo) {
+    for companion in companions::find_companions(&photo.og_path) {
+        let dest = companions::companion_destination(&companion, &photo.og_path, &photo.db_path);
+        fs::remove_file(dest).ok();
+    }
+    fs::remove_file(&photo.db_path)
+        .map(|_| {
+            events::emit(Event::Deleted { path: events::path(&photo.db_path), dry_run: false })
+        })
+        .map_err(|e| {
+            events::emit(Event::error(Category::Delete, &photo.db_path, format!("removing: {}", e)))
+        })
+        .ok();
+}
+
+/// Deletes the source of a committed photo and its companions once each copy is verified. The
+/// ones that could not be deleted are added to `refused`. Returns whether all were deleted.
+fn delete_sources(photo: &Photo, refused: &Mutex<Vec<PhotoDBError>>) -> bool {
+    let deleted =
+        transfer::delete_verified_source(&photo.og_path, &photo.db_path, photo.hash as i64).map(
+            |_| events::emit(Event::Deleted { path: events::path(&photo.og_path), dry_run: false }),
+        );
+    let companions_deleted =
+        companions::find_companions(&photo.og_path).into_iter().map(|companion| {
+            let dest =
+                companions::companion_destination(&companion, &photo.og_path, &photo.db_path);
+            transfer::delete_verified_companion(&companion, &dest).map(|_| {
+                events::emit(Event::Deleted { path: events::path(&companion), dry_run: false })
+            })
+        });
+    let mut all_deleted = true;
+    for e in std::iter::once(deleted).chain(companions_deleted).filter_map(Result::err) {
+        events::emit(Event::failed(Category::Delete, &e));
+        refused.lock().unwrap().push(e);
+        all_deleted = false;
+    }
+    all_deleted
+}
+
+fn get_img_file_list(
+    path_to_import: &PathBuf, formats: &Formats, filter: &ImportFilter,
+) -> Vec<PathBuf> {
+    let options: MatchOptions = Default::default();
+    let mut ignore_files = IgnoreFiles::default();
+    let img_files: Vec<_> =
+        glob_with(path_to_import.join("**/*").as_os_str().to_str().expect("join"), options)
+            .unwrap()
+            .filter_map(|x| x.ok())
+            .filter(|path| is_wanted_file(path, path_to_import, formats, filter, &mut ignore_files))
+            .collect();
+    img_files
+}
+
+/// Whether an import of `root` picks up `path`: an image that no filter or ignore file excludes.
+fn is_wanted_file(
+    path: &Path, root: &PathBuf, formats: &Formats, filter: &ImportFilter,
+    ignore_files: &mut IgnoreFiles,
+) -> bool {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    filter.accepts_file(path, relative)
+        && !ignore_files.ignores(root, path)
+        && formats.is_image_file(path)
+}
+
+/// Imports the images already in `options.source`, then every image dropped into it, until
+/// SIGTERM or SIGINT. Files are imported once their size has stopped changing, in batches.
+#[allow(clippy::too_many_arguments)]
+pub fn watch_directory(
+    options: &ImportOptions, filter: &ImportFilter, watch_options: &WatchOptions,
+    import_path: &Path, rescan: bool, database: &Pool<ConnectionManager<SqliteConnection>>,
+    formats: &Formats, template: &PathTemplate,
+) {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))
+            .unwrap_or_else(|e| events::fatal(Event::error(Category::Input, Path::new(""), e)));
+    }
+    // The watch is registered before the first scan so that no file dropped in during it is
+    // missed. Files seen by both are skipped as duplicates the second time.
+    let initial = || {
+        import_directory(options, filter, import_path, rescan, None, database, formats, template);
+        events::note(format!("Watching {}", options.source.display()));
+    };
+    let import = |files: Vec<PathBuf>| {
+        // Ignore files can change between batches, so they are read again for each one.
+        let mut ignore_files = IgnoreFiles::default();
+        let img_files: Vec<PathBuf> = files
+            .into_iter()
+            .filter(|path| {
+                is_wanted_file(path, &options.source, formats, filter, &mut ignore_files)
+            })
+            .collect();
+        match img_files.is_empty() {
+            true => Ok(()),
+            false => import_files(
+                options,
+                filter,
+                img_files,
+                import_path,
+                rescan,
+                None,
+                database,
+                template,
+            ),
+        }
+    };
+    watch::watch_folder(&options.source, watch_options, &stop, initial, import)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    events::note(format!("Stopped watching {}", options.source.display()));
+}
+
+/// Picks each photo's destination in capture order: the lowest free `{seq}`, then a name that
+/// does not replace a different photo already in the library or earlier in the import.
+fn place_photos(photos: &mut [Photo], template: &PathTemplate) {
+    photos.sort_by(|a, b| (a.exif_date, &a.og_path).cmp(&(b.exif_date, &b.og_path)));
+    let mut taken = HashSet::new();
+    for photo in photos.iter_mut() {
+        let rendered = template.render_unused(&photo.db_root, &photo.to_db_row(), None, &taken);
+        let path = util::collision_free_path(&rendered, photo.hash as i64, |p| {
+            !taken.contains(p)
+                && (!p.exists() || Photo::hash_file(&p.to_path_buf()).ok() == Some(photo.hash))
+        });
+        if path != rendered {
+            events::note(format!("name collision: {} -> {}", rendered.display(), path.display()));
+        }
+        taken.insert(path.clone());
+        photo.db_path = path;
+    }
+}
+
+fn get_photos_from_img_file_list(
+    img_files: &Vec<PathBuf>, import_path: &Path, template: &PathTemplate,
+) -> Vec<Photo> {
+    let photo_vec: Vec<Photo> = img_files
+        .par_iter()
+        .filter_map(|path| {
+            fs::read(path)
+                .map_err(|e| {
+                    events::emit(Event::error(Category::Read, path, format!("reading file: {}", e)))
+                })
+                .as_ref()
+                .ok()
+                .and_then(|buf| {
+                    let photo = Photo::new(buf, path, import_path, template)
+                        .map_err(|e| events::emit(Event::failed(Category::Decode, &e)))
+                        .ok()?;
+                    events::emit(Event::Hashed {
+                        path: events::path(path),
+                        hash: photo.hash as i64,
+                    });
+                    Some(photo)
+                })
+        })
+        .collect();
+    photo_vec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::PhotoMetadata;
+    use crate::plan::Operation;
+    use std::io::Write;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("photodb-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn photo(source: &Path, db_root: &Path) -> Photo {
+        Photo {
+            hash: 42,
+            year: 2024,
+            month: 6,
+            model: "ILCE-7M3".to_string(),
+            exif_date: 0,
+            exif_json: "{}".to_string(),
+            metadata: PhotoMetadata::default(),
+            db_root: db_root.to_path_buf(),
+            db_path: db_root.join("2024/06/DSC0001.ARW"),
+            og_path: source.to_path_buf(),
+            link: LinkMode::Copy,
+        }
+    }
+
+    fn options(source: &Path, copy_files: bool, insert: bool) -> ImportOptions {
+        ImportOptions {
+            source: source.to_path_buf(),
+            copy_files,
+            insert,
+            delete_source: false,
+            link: LinkMode::Copy.as_str().to_string(),
+            xattrs: false,
+        }
+    }
+
+    #[test]
+    fn plans_without_copy_place_nothing() {
+        let dir = scratch_dir("import-plan-no-copy");
+        let (card, db_root) = (dir.join("card"), dir.join("library"));
+        fs::create_dir_all(&card).unwrap();
+        let source = card.join("DSC0001.ARW");
+        fs::write(&source, b"raw").unwrap();
+        let plan_path = dir.join("plan.json");
+
+        write_import_plan(
+            &[photo(&source, &db_root)],
+            &options(&card, false, false),
+            &db_root,
+            &plan_path,
+        );
+
+        let plan = Plan::read(&plan_path).unwrap();
+        assert!(!plan.operations.iter().any(|op| matches!(op, Operation::Place { .. })));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn plans_with_copy_and_insert_place_each_photo() {
+        let dir = scratch_dir("import-plan-copy");
+        let (card, db_root) = (dir.join("card"), dir.join("library"));
+        fs::create_dir_all(&card).unwrap();
+        let source = card.join("DSC0001.ARW");
+        fs::write(&source, b"raw").unwrap();
+        let plan_path = dir.join("plan.json");
+
+        write_import_plan(
+            &[photo(&source, &db_root)],
+            &options(&card, true, true),
+            &db_root,
+            &plan_path,
+        );
+
+        let plan = Plan::read(&plan_path).unwrap();
+        assert!(matches!(plan.operations.as_slice(), [Operation::Place { .. }]));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn resume_skips_steps_recorded_before_a_cut_short_line() {
+        let dir = scratch_dir("import-resume-truncated");
+        let (card, db_root) = (dir.join("card"), dir.join("library"));
+        fs::create_dir_all(&card).unwrap();
+        fs::create_dir_all(db_root.join(".photodb")).unwrap();
+        let pool = db::get_connection_pool_for(&db::build_config_path(&db_root)).unwrap();
+        let options = options(&card, true, true);
+        let mut photos = Vec::new();
+        for (hash, name) in [(1, "DSC0001.ARW"), (2, "DSC0002.ARW")] {
+            let source = card.join(name);
+            fs::write(&source, name).unwrap();
+            let mut photo = photo(&source, &db_root);
+            photo.hash = hash;
+            photo.db_path = db_root.join("2024/06").join(name);
+            fs::create_dir_all(photo.db_path.parent().unwrap()).unwrap();
+            fs::copy(&source, &photo.db_path).unwrap();
+            db::insert_file_to_db(&photo.to_db_row(), &pool).unwrap();
+            photos.push(photo);
+        }
+        // Both photos were copied and committed, but the run was killed while recording that the
+        // second one was inserted.
+        let mut journal = ImportJournal::create(&db_root, &options).unwrap();
+        for photo in photos.iter() {
+            let (hash, path, dest) =
+                (photo.hash as i64, photo.og_path.clone(), photo.db_path.clone());
+            journal.append(&Record::Planned { hash, path, dest }).unwrap();
+        }
+        for record in [
+            Record::Copied { hash: 1, link: "copy".to_string() },
+            Record::Inserted { hash: 1 },
+            Record::Copied { hash: 2, link: "copy".to_string() },
+        ] {
+            journal.append(&record).unwrap();
+        }
+        let id = journal.id.clone();
+        let journal_path = db_root.join(".photodb/journal").join(format!("import-{}.jsonl", id));
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&journal_path)
+            .unwrap()
+            .write_all(b"{\"op\":\"ins")
+            .unwrap();
+
+        let pending = import_journal::read_pending(&db_root, &id).unwrap();
+        let unfinished = pending.unfinished();
+        assert_eq!(unfinished.iter().map(|p| p.hash).collect::<Vec<_>>(), vec![2]);
+        let journal = Mutex::new(ImportJournal::open(&db_root, &id).unwrap());
+        let resumed = photos.into_iter().filter(|photo| photo.hash == 2).collect();
+        let progress =
+            place_photos_in_library(resumed, &options, Some(&pending), Some(&journal), &pool);
+
+        assert_eq!((progress.copied, progress.inserted, progress.finished), (0, 1, 1));
+        assert_eq!(db::get_photos(&pool).unwrap().len(), 2);
+        let library: Vec<_> = fs::read_dir(db_root.join("2024/06")).unwrap().collect();
+        assert_eq!(library.len(), 2);
+        finish_journal(Some(journal), true);
+        assert!(import_journal::unfinished_ids(&db_root).is_empty());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,339 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use glob::{MatchOptions, Pattern};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::models;
+use crate::timeshift::parse_local_time;
+
+/// Name of the file listing paths an import skips, read from every directory of the walk.
+pub const IGNORE_FILE: &str = ".photodbignore";
+
+/// Which files an import picks up. Path and size filters are checked during the walk, before any
+/// file is read; capture time and camera filters once the file is decoded.
+#[derive(Clone, Debug, Default)]
+pub struct ImportFilter {
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+/// Parses the start of a capture time range, e.g. `2023-06-01` or `2023-06-01 12:00:00`.
+pub fn parse_since(time: &str) -> Result<NaiveDateTime, String> {
+    parse_local_time(time)
+}
+
+/// Parses the end of a capture time range. A bare day includes the whole day.
+pub fn parse_until(time: &str) -> Result<NaiveDateTime, String> {
+    match NaiveDate::parse_from_str(time.trim(), "%Y-%m-%d") {
+        Ok(day) => Ok(day.and_hms_opt(23, 59, 59).expect("valid time")),
+        Err(_) => parse_local_time(time),
+    }
+}
+
+/// Parses a glob matched against paths relative to the import root, e.g. `**/_rejects/**`.
+pub fn parse_glob(glob: &str) -> Result<Pattern, String> {
+    Pattern::new(glob).map_err(|e| format!("invalid glob '{}': {}", glob, e))
+}
+
+/// Parses a file size such as `500`, `20K`, `15M` or `1G`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("invalid size '{}', expected e.g. 20M", size)),
+    };
+    number
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("invalid size '{}', expected e.g. 20M", size))
+}
+
+fn glob_options() -> MatchOptions {
+    MatchOptions { require_literal_separator: true, ..Default::default() }
+}
+
+impl ImportFilter {
+    /// Whether the file at `path`, `relative` to the import root, passes the path and size filters.
+    pub fn accepts_file(&self, path: &Path, relative: &Path) -> bool {
+        if !self.accepts_path(relative) {
+            return false;
+        }
+        if self.min_size.is_none() && self.max_size.is_none() {
+            return true;
+        }
+        fs::metadata(path).map_or(true, |metadata| self.accepts_size(metadata.len()))
+    }
+
+    /// Whether an archive entry at `relative` inside the archive passes the path and size filters.
+    pub fn accepts_entry(&self, relative: &Path, size: u64) -> bool {
+        self.accepts_path(relative) && self.accepts_size(size)
+    }
+
+    fn accepts_path(&self, relative: &Path) -> bool {
+        let options = glob_options();
+        (self.include.is_empty()
+            || self.include.iter().any(|p| p.matches_path_with(relative, options)))
+            && !self.exclude.iter().any(|p| p.matches_path_with(relative, options))
+    }
+
+    fn accepts_size(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    /// Whether a decoded photo passes the capture time and camera filters.
+    pub fn accepts_photo(&self, row: &models::Photo) -> bool {
+        let local = row.capture_time().local;
+        let matches = |wanted: &Option<String>, value: &Option<String>| match wanted {
+            Some(wanted) => value.as_ref().is_some_and(|v| v.trim().eq_ignore_ascii_case(wanted)),
+            None => true,
+        };
+        self.since.is_none_or(|since| local >= since)
+            && self.until.is_none_or(|until| local <= until)
+            && matches(&self.model, &row.camera_model)
+            && matches(&self.serial, &row.serial)
+    }
+
+    pub fn filters_photos(&self) -> bool {
+        self.since.is_some()
+            || self.until.is_some()
+            || self.model.is_some()
+            || self.serial.is_some()
+    }
+}
+
+/// The patterns of one `.photodbignore`, matched against paths relative to its directory.
+struct IgnoreFile {
+    dir: PathBuf,
+    patterns: Vec<IgnorePattern>,
+}
+
+struct IgnorePattern {
+    pattern: Pattern,
+    /// Patterns without a `/` match a file or directory name at any depth.
+    anywhere: bool,
+    /// Patterns ending in `/` only match directories.
+    dir_only: bool,
+    /// Patterns starting with `!` take back what an earlier line ignored.
+    negated: bool,
+}
+
+impl IgnorePattern {
+    /// Whether the pattern matches `path`, or one of the directories between it and the file.
+    fn matches(&self, relative: &Path) -> bool {
+        let components = relative.ancestors().filter(|a| !a.as_os_str().is_empty());
+        components.enumerate().any(|(depth, prefix)| {
+            let is_dir = depth > 0;
+            if self.dir_only && !is_dir {
+                return false;
+            }
+            match self.anywhere {
+                true => prefix
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| self.pattern.matches(name)),
+                false => self.pattern.matches_path_with(prefix, glob_options()),
+            }
+        })
+    }
+}
+
+impl IgnoreFile {
+    /// Reads an ignore file: one glob per line, `#` starts a comment. Invalid globs are skipped.
+    fn read(path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let negated = line.starts_with('!');
+                let line = line.trim_start_matches('!');
+                let dir_only = line.ends_with('/');
+                let line = line.trim_end_matches('/');
+                let anywhere = !line.contains('/');
+                let pattern = Pattern::new(line.trim_start_matches('/')).ok()?;
+                Some(IgnorePattern { pattern, anywhere, dir_only, negated })
+            })
+            .collect();
+        Some(IgnoreFile { dir: path.parent()?.to_path_buf(), patterns })
+    }
+
+    /// Whether `path` is ignored, as decided by the last line that matches it, or `None` if no
+    /// line does.
+    fn ignores(&self, path: &Path) -> Option<bool> {
+        let relative = path.strip_prefix(&self.dir).ok()?;
+        self.patterns.iter().rev().find(|p| p.matches(relative)).map(|p| !p.negated)
+    }
+}
+
+/// The `.photodbignore` files found under an import root, read once per directory.
+#[derive(Default)]
+pub struct IgnoreFiles {
+    files: HashMap<PathBuf, Option<IgnoreFile>>,
+}
+
+impl IgnoreFiles {
+    /// Whether an ignore file in `root` or one of the directories below it, down to `path`,
+    /// ignores `path`. The file closest to `path` that has a matching line decides.
+    pub fn ignores(&mut self, root: &Path, path: &Path) -> bool {
+        let dirs: Vec<PathBuf> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(root))
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.into_iter()
+            .find_map(|dir| {
+                self.files
+                    .entry(dir.clone())
+                    .or_insert_with(|| IgnoreFile::read(&dir.join(IGNORE_FILE)))
+                    .as_ref()
+                    .and_then(|file| file.ignores(path))
+            })
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(exif_date: i64, model: Option<&str>, serial: Option<&str>) -> models::Photo {
+        models::Photo {
+            hash: 42,
+            original_path: "/card/DSC0001.ARW".to_string(),
+            current_path: String::new(),
+            exif_json: "{}".to_string(),
+            exif_date,
+            camera_make: None,
+            camera_model: model.map(str::to_string),
+            lens: None,
+            serial: serial.map(str::to_string),
+            iso: None,
+            shutter: None,
+            aperture: None,
+            focal_length: None,
+            metadata_sources: "{}".to_string(),
+            date_source: Some("exif".to_string()),
+            utc_offset: None,
+            subsec_ms: None,
+            link_type: "copy".to_string(),
+        }
+    }
+
+    fn time(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("500B"), Ok(500));
+        assert_eq!(parse_size("20K"), Ok(20 << 10));
+        assert_eq!(parse_size("15mb"), Ok(15 << 20));
+        assert_eq!(parse_size(" 1G "), Ok(1 << 30));
+        for size in ["", "M", "1.5M", "10T", "-1", "1 M"] {
+            assert!(parse_size(size).is_err(), "accepted '{}'", size);
+        }
+    }
+
+    #[test]
+    fn parses_time_ranges() {
+        assert_eq!(parse_since("2023-06-01"), Ok(time("2023-06-01 00:00:00")));
+        assert_eq!(parse_until("2023-06-01"), Ok(time("2023-06-01 23:59:59")));
+        assert_eq!(parse_until("2023-06-01 12:00:00"), Ok(time("2023-06-01 12:00:00")));
+        assert!(parse_since("June").is_err());
+    }
+
+    #[test]
+    fn matches_include_and_exclude_globs() {
+        let filter = ImportFilter {
+            include: vec![parse_glob("**/*.ARW").unwrap()],
+            exclude: vec![parse_glob("**/_rejects/**").unwrap()],
+            ..Default::default()
+        };
+        assert!(filter.accepts_entry(Path::new("DSC0001.ARW"), 1));
+        assert!(filter.accepts_entry(Path::new("2023/06/DSC0001.ARW"), 1));
+        assert!(!filter.accepts_entry(Path::new("2023/06/DSC0001.NEF"), 1));
+        assert!(!filter.accepts_entry(Path::new("2023/_rejects/DSC0001.ARW"), 1));
+        let filter = ImportFilter { include: vec![parse_glob("*.ARW").unwrap()], ..filter };
+        assert!(!filter.accepts_entry(Path::new("2023/DSC0001.ARW"), 1));
+        assert!(parse_glob("[").is_err());
+    }
+
+    #[test]
+    fn checks_sizes() {
+        let filter = ImportFilter { min_size: Some(10), max_size: Some(20), ..Default::default() };
+        assert!(!filter.accepts_entry(Path::new("a.ARW"), 9));
+        assert!(filter.accepts_entry(Path::new("a.ARW"), 10));
+        assert!(filter.accepts_entry(Path::new("a.ARW"), 20));
+        assert!(!filter.accepts_entry(Path::new("a.ARW"), 21));
+    }
+
+    #[test]
+    fn filters_by_capture_time() {
+        let filter = ImportFilter {
+            since: Some(time("2023-06-01 00:00:00")),
+            until: Some(time("2023-06-01 23:59:59")),
+            ..Default::default()
+        };
+        assert!(filter.filters_photos());
+        let at = |t: &str| row(time(t).and_utc().timestamp(), None, None);
+        assert!(!filter.accepts_photo(&at("2023-05-31 23:59:59")));
+        assert!(filter.accepts_photo(&at("2023-06-01 00:00:00")));
+        assert!(filter.accepts_photo(&at("2023-06-01 23:59:59")));
+        assert!(!filter.accepts_photo(&at("2023-06-02 00:00:00")));
+    }
+
+    #[test]
+    fn filters_by_camera() {
+        let filter = ImportFilter {
+            model: Some("ilce-7m3".to_string()),
+            serial: Some("123".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.accepts_photo(&row(0, Some("ILCE-7M3 "), Some("123"))));
+        assert!(!filter.accepts_photo(&row(0, Some("ILCE-7M4"), Some("123"))));
+        assert!(!filter.accepts_photo(&row(0, Some("ILCE-7M3"), None)));
+        assert!(!ImportFilter::default().filters_photos());
+        assert!(ImportFilter::default().accepts_photo(&row(0, None, None)));
+    }
+
+    #[test]
+    fn reads_ignore_files() {
+        let root = std::env::temp_dir().join(format!("photodb-ignore-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("2023/keep")).unwrap();
+        let ignore = "# culled\n_rejects/\n!_rejects/keepers/\n*.tmp\n/2019/**/*.DNG\nkeep\n";
+        fs::write(root.join(IGNORE_FILE), ignore).unwrap();
+        fs::write(root.join("2023").join(IGNORE_FILE), "!*.tmp\n").unwrap();
+        let mut files = IgnoreFiles::default();
+        let mut ignores = |path: &str| files.ignores(&root, &root.join(path));
+
+        assert!(!ignores("DSC0001.ARW"));
+        assert!(ignores("_rejects/DSC0001.ARW"));
+        assert!(ignores("2023/_rejects/DSC0001.ARW"));
+        assert!(!ignores("_rejects/keepers/DSC0001.ARW"));
+        assert!(!ignores("_rejects"), "directory patterns only match directories");
+        assert!(ignores("a.tmp"));
+        assert!(!ignores("2023/a.tmp"), "deeper files override the ones above");
+        assert!(ignores("2019/06/01/DSC0001.DNG"));
+        assert!(!ignores("2020/2019/DSC0001.DNG"));
+        assert!(ignores("2023/keep/DSC0001.ARW"));
+        fs::remove_dir_all(&root).ok();
+    }
+}
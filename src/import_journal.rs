@@ -0,0 +1,153 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::journal::{build_journal_path, Journal};
+use crate::photodb_error::PhotoDBError;
+
+const JOURNAL_PREFIX: &str = "import-";
+
+/// The options an import was started with, so it can be resumed the same way.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImportOptions {
+    pub source: PathBuf,
+    // Journals written before the flag was renamed from `--move-files`.
+    #[serde(alias = "move_files")]
+    pub copy_files: bool,
+    pub insert: bool,
+    pub delete_source: bool,
+    pub link: String,
+    pub xattrs: bool,
+}
+
+/// One step of an import. Copies are verified before they are renamed into place, so `Copied` is
+/// only written for a verified copy, and `Deleted` only once the copy was re-hashed.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Record {
+    Started { options: ImportOptions },
+    Planned { hash: i64, path: PathBuf, dest: PathBuf },
+    Copied { hash: i64, link: String },
+    Inserted { hash: i64 },
+    Deleted { hash: i64 },
+}
+
+/// A photo an import planned to place at `dest`.
+#[derive(Clone, Debug)]
+pub struct PlannedPhoto {
+    pub hash: i64,
+    pub path: PathBuf,
+    pub dest: PathBuf,
+}
+
+/// What an interrupted import still has to do.
+pub struct PendingImport {
+    pub id: String,
+    pub options: ImportOptions,
+    pub planned: Vec<PlannedPhoto>,
+    /// The link type each copied photo ended up with.
+    pub copied: HashMap<i64, String>,
+    pub inserted: HashSet<i64>,
+    pub deleted: HashSet<i64>,
+}
+
+impl PendingImport {
+    /// Planned photos with steps left: a copy or row that is missing, or a source to delete.
+    pub fn unfinished(&self) -> Vec<PlannedPhoto> {
+        self.planned
+            .iter()
+            .filter(|photo| {
+                !self.inserted.contains(&photo.hash)
+                    || (self.options.delete_source && !self.deleted.contains(&photo.hash))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// The journal of one import, named `import-<id>.jsonl`.
+pub struct ImportJournal {
+    pub id: String,
+    journal: Journal,
+}
+
+fn build_import_journal_path(db_root: &Path, id: &str) -> PathBuf {
+    build_journal_path(db_root, format!("{}{}", JOURNAL_PREFIX, id).as_str())
+}
+
+impl ImportJournal {
+    /// Starts the journal of a new import, identified by the time it started.
+    pub fn create(db_root: &Path, options: &ImportOptions) -> Result<Self, PhotoDBError> {
+        let id = Local::now().format("%Y%m%d-%H%M%S-%3f").to_string();
+        let mut journal = Self::open(db_root, &id)?;
+        journal.append(&Record::Started { options: options.clone() })?;
+        Ok(journal)
+    }
+
+    /// Reopens the journal of import `id` to record the steps of a resumed run.
+    pub fn open(db_root: &Path, id: &str) -> Result<Self, PhotoDBError> {
+        let journal = Journal::open(&build_import_journal_path(db_root, id))?;
+        Ok(ImportJournal { id: id.to_string(), journal })
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<(), PhotoDBError> {
+        self.journal.append(record)
+    }
+
+    /// Removes the journal once every planned photo is done.
+    pub fn finish(self) -> Result<(), PhotoDBError> {
+        self.journal.finish()
+    }
+}
+
+/// Reads the journal of import `id`.
+pub fn read_pending(db_root: &Path, id: &str) -> Result<PendingImport, PhotoDBError> {
+    let path = build_import_journal_path(db_root, id);
+    let records = Journal::read::<Record>(&path)?
+        .ok_or_else(|| PhotoDBError::new("no unfinished import with this id", &path))?;
+    let mut options = None;
+    let mut planned = Vec::new();
+    let (mut copied, mut inserted, mut deleted) = (HashMap::new(), HashSet::new(), HashSet::new());
+    for record in records {
+        match record {
+            Record::Started { options: started } => options = Some(started),
+            Record::Planned { hash, path, dest } => planned.push(PlannedPhoto { hash, path, dest }),
+            Record::Copied { hash, link } => {
+                copied.insert(hash, link);
+            }
+            Record::Inserted { hash } => {
+                inserted.insert(hash);
+            }
+            Record::Deleted { hash } => {
+                deleted.insert(hash);
+            }
+        }
+    }
+    Ok(PendingImport {
+        id: id.to_string(),
+        options: options.ok_or_else(|| PhotoDBError::new("journal has no start record", &path))?,
+        planned,
+        copied,
+        inserted,
+        deleted,
+    })
+}
+
+/// The ids of imports that were interrupted before they finished, oldest first.
+pub fn unfinished_ids(db_root: &Path) -> Vec<String> {
+    let pattern = build_import_journal_path(db_root, "*");
+    let mut ids: Vec<String> = glob::glob(pattern.to_str().expect("join"))
+        .map(|paths| {
+            paths
+                .filter_map(|path| path.ok())
+                .filter_map(|path| {
+                    let stem = path.file_stem()?.to_str()?;
+                    stem.strip_prefix(JOURNAL_PREFIX).map(|id| id.to_string())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ids.sort();
+    ids
+}
@@ -0,0 +1,105 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::photodb_error::PhotoDBError;
+
+pub fn build_journal_path(db_root: &Path, name: &str) -> PathBuf {
+    db_root.join(".photodb").join("journal").join(format!("{}.jsonl", name))
+}
+
+/// An append-only log of JSON records, one per line. Every record is synced to disk before
+/// `append` returns, so an interrupted operation can be resumed from what was written.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, creating it if needed. A last line cut short by
+    /// a crash is removed first, so that the next record starts on a line of its own.
+    pub fn open(path: &PathBuf) -> Result<Self, PhotoDBError> {
+        fs::create_dir_all(path.parent().unwrap())
+            .map_err(|e| PhotoDBError::new(format!("creating journal: {}", e).as_str(), path))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|file| {
+                let contents = fs::read(path)?;
+                let complete = contents.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+                if complete < contents.len() {
+                    file.set_len(complete as u64)?;
+                }
+                Ok(file)
+            })
+            .map_err(|e| PhotoDBError::new(format!("opening journal: {}", e).as_str(), path))?;
+        Ok(Journal { path: path.to_path_buf(), file })
+    }
+
+    pub fn append<T: Serialize>(&mut self, record: &T) -> Result<(), PhotoDBError> {
+        let mut line = serde_json::to_string(record).map_err(|e| {
+            PhotoDBError::new(format!("writing journal: {}", e).as_str(), &self.path)
+        })?;
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| PhotoDBError::new(format!("writing journal: {}", e).as_str(), &self.path))
+    }
+
+    /// Reads every record of the journal at `path`, or `None` if there is no journal. A last line
+    /// cut short by a crash is ignored.
+    pub fn read<T: DeserializeOwned>(path: &PathBuf) -> Result<Option<Vec<T>>, PhotoDBError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| PhotoDBError::new(format!("reading journal: {}", e).as_str(), path))?;
+        let lines: Vec<&str> = contents.lines().filter(|line| !line.trim().is_empty()).collect();
+        let mut records = Vec::with_capacity(lines.len());
+        for (i, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(_) if i + 1 == lines.len() => break,
+                Err(e) => {
+                    return Err(PhotoDBError::new(
+                        format!("reading journal line {}: {}", i + 1, e).as_str(),
+                        path,
+                    ))
+                }
+            }
+        }
+        Ok(Some(records))
+    }
+
+    /// Removes the journal once the operation it records has completed.
+    pub fn finish(self) -> Result<(), PhotoDBError> {
+        fs::remove_file(&self.path)
+            .map_err(|e| PhotoDBError::new(format!("removing journal: {}", e).as_str(), &self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_after_a_cut_short_line() {
+        let dir = std::env::temp_dir().join(format!("photodb-journal-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let path = build_journal_path(&dir, "test");
+        Journal::open(&path).unwrap().append(&1).unwrap();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"2").unwrap();
+        assert_eq!(Journal::read::<i32>(&path).unwrap(), Some(vec![1, 2]));
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"[").unwrap();
+        assert_eq!(Journal::read::<i32>(&path).unwrap(), Some(vec![1]));
+
+        Journal::open(&path).unwrap().append(&3).unwrap();
+
+        assert_eq!(Journal::read::<i32>(&path).unwrap(), Some(vec![1, 3]));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,6 +1,30 @@
+pub mod archive;
+pub mod clean;
+pub mod companions;
+pub mod config;
+pub mod dates;
 pub mod db;
+pub mod events;
+pub mod exif;
+pub mod formats;
+pub mod import;
+pub mod import_filter;
+pub mod import_journal;
+pub mod journal;
+pub mod library;
+pub mod metadata;
 pub mod models;
+pub mod path_template;
 pub mod photodb_error;
+pub mod plan;
 pub mod raw_photo;
+pub mod reorganize;
+pub mod scan_cache;
 pub mod schema;
+pub mod sync;
+pub mod thumbnail;
+pub mod timeshift;
+pub mod transfer;
 pub mod util;
+pub mod verify;
+pub mod watch;
@@ -0,0 +1,85 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use rayon::prelude::*;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::metadata::WEAK_DATE_SOURCES;
+use crate::photodb_error::PhotoDBError;
+use crate::thumbnail;
+
+/// Writes the thumbnail of every photo in the library, first deleting the cache with `rebuild`.
+pub fn build_thumbnails(
+    db_root: &Path, rebuild: bool, database: &Pool<ConnectionManager<SqliteConnection>>,
+) {
+    let thumbs_dir = db_root.join(".photodb").join("thumbs");
+    if rebuild {
+        events::note(format!("Removing thumbnail cache {}", thumbs_dir.display()));
+        match fs::remove_dir_all(&thumbs_dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                let message = format!("removing thumbnail cache: {}", e);
+                events::fatal(Event::error(Category::Delete, &thumbs_dir, message));
+            }
+            _ => {}
+        }
+    }
+    let photos: Vec<_> = db::get_photos(database)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)))
+        .into_iter()
+        .collect();
+    events::note(format!("Building thumbnails for {} photos", photos.len()));
+    let built: usize = photos
+        .par_iter()
+        .map(|photo| {
+            let path = PathBuf::from(&photo.current_path);
+            fs::read(&path)
+                .map_err(|e| PhotoDBError::new(format!("reading file: {}", e).as_str(), &path))
+                .and_then(|buf| thumbnail::write_thumbnail(&buf, &path, db_root, photo.hash))
+                .map(|thumb_path| {
+                    events::emit(Event::Thumbnail {
+                        path: events::path(&path),
+                        thumbnail: events::path(&thumb_path),
+                    })
+                })
+                .map_err(|e| events::emit(Event::failed(Category::Decode, &e)))
+                .map_or(0, |_| 1)
+        })
+        .sum();
+    events::note(format!("Built {}/{} thumbnails", built, photos.len()));
+    events::summary("thumbs", vec![("photos", photos.len()), ("thumbnails", built)]);
+}
+
+/// Lists the photos whose capture date was guessed from the file name, folder or mtime.
+pub fn report_weak_dates(database: &Pool<ConnectionManager<SqliteConnection>>) {
+    let sources: Vec<&str> = WEAK_DATE_SOURCES.iter().map(|s| s.as_str()).collect();
+    let photos = db::get_photos_by_date_source(&sources, database)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    for photo in photos.iter() {
+        events::emit(Event::WeakDate {
+            path: photo.current_path.clone(),
+            capture_time: photo.capture_time().local.format("%Y-%m-%d %H:%M:%S").to_string(),
+            date_source: photo.date_source.clone().unwrap_or_default(),
+        });
+    }
+    events::note(format!("Found {} photos with a weak date source", photos.len()));
+    events::summary("weak-dates", vec![("photos", photos.len())]);
+}
+
+/// Finds where the photos imported from a file name or path are stored.
+pub fn lookup_original(name: &str, database: &Pool<ConnectionManager<SqliteConnection>>) {
+    let photos = db::get_photos_by_original_name(name, database)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    for photo in photos.iter() {
+        events::emit(Event::Located {
+            original_path: photo.original_path.clone(),
+            current_path: photo.current_path.clone(),
+        });
+    }
+    if photos.is_empty() {
+        let message = "no photo was imported from this file";
+        events::fatal(Event::error(Category::Input, Path::new(name), message));
+    }
+}
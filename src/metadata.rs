@@ -0,0 +1,162 @@
+use chrono::{Local, TimeZone};
+use libraw_rs_vendor::libraw_data_t;
+use rexiv2::Metadata;
+use std::collections::BTreeMap;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::dates::{find_date, CaptureTime};
+use crate::photodb_error::PhotoDBError;
+
+/// Where a metadata value was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataSource {
+    Exif,
+    Xmp,
+    Gps,
+    Libraw,
+    Filename,
+    Folder,
+    Mtime,
+}
+
+/// Date sources that are guesses rather than recorded by the camera.
+pub const WEAK_DATE_SOURCES: [MetadataSource; 3] =
+    [MetadataSource::Filename, MetadataSource::Folder, MetadataSource::Mtime];
+
+impl MetadataSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetadataSource::Exif => "exif",
+            MetadataSource::Xmp => "xmp",
+            MetadataSource::Gps => "gps",
+            MetadataSource::Libraw => "libraw",
+            MetadataSource::Filename => "filename",
+            MetadataSource::Folder => "folder",
+            MetadataSource::Mtime => "mtime",
+        }
+    }
+
+    pub fn is_weak(&self) -> bool {
+        WEAK_DATE_SOURCES.contains(self)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sourced<T> {
+    pub value: T,
+    pub source: MetadataSource,
+}
+
+/// The capture metadata stored for every photo. Values come from EXIF when `rexiv2` can read
+/// them and from libraw otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct PhotoMetadata {
+    pub date: Option<Sourced<CaptureTime>>,
+    pub make: Option<Sourced<String>>,
+    pub model: Option<Sourced<String>>,
+    pub lens: Option<Sourced<String>>,
+    pub serial: Option<Sourced<String>>,
+    pub iso: Option<Sourced<i32>>,
+    pub shutter: Option<Sourced<f64>>,
+    pub aperture: Option<Sourced<f64>>,
+    pub focal_length: Option<Sourced<f64>>,
+}
+
+impl PhotoMetadata {
+    /// Reads every field from EXIF, falling back to libraw field by field. The date goes through
+    /// the longer chain in [`find_date`]. `libraw` must be the data of a file libraw opened.
+    pub fn read(
+        exif: &Result<Metadata, PhotoDBError>, libraw: &libraw_data_t, og_path: &Path,
+    ) -> Self {
+        let exif = exif.as_ref().ok();
+        Self {
+            date: find_date(exif, libraw_timestamp(libraw.other.timestamp), og_path),
+            make: pick(
+                exif.and_then(|e| exif_string(e, "Exif.Image.Make")),
+                c_string(&libraw.idata.make),
+            ),
+            model: pick(
+                exif.and_then(|e| exif_string(e, "Exif.Image.Model")),
+                c_string(&libraw.idata.model).or_else(|| c_string(&libraw.idata.make)),
+            ),
+            lens: pick(
+                exif.and_then(|e| exif_string(e, "Exif.Photo.LensModel")),
+                c_string(&libraw.lens.Lens),
+            ),
+            serial: pick(
+                exif.and_then(|e| exif_string(e, "Exif.Photo.BodySerialNumber")),
+                c_string(&libraw.shootinginfo.BodySerial),
+            ),
+            iso: pick(
+                exif.and_then(|e| e.get_iso_speed()).filter(|v| *v > 0),
+                positive(libraw.other.iso_speed).map(|v| v.round() as i32),
+            ),
+            shutter: pick(
+                exif.and_then(|e| e.get_exposure_time())
+                    .filter(|t| *t.denom() != 0)
+                    .map(|t| *t.numer() as f64 / *t.denom() as f64)
+                    .filter(|v| *v > 0.0),
+                positive(libraw.other.shutter),
+            ),
+            aperture: pick(
+                exif.and_then(|e| e.get_fnumber()).filter(|v| *v > 0.0),
+                positive(libraw.other.aperture),
+            ),
+            focal_length: pick(
+                exif.and_then(|e| e.get_focal_length()).filter(|v| *v > 0.0),
+                positive(libraw.other.focal_len),
+            ),
+        }
+    }
+
+    /// JSON object mapping each populated field to the source it was read from.
+    pub fn sources_json(&self) -> String {
+        let mut sources = BTreeMap::new();
+        let mut record = |field: &'static str, source: Option<MetadataSource>| {
+            if let Some(source) = source {
+                sources.insert(field, source.as_str());
+            }
+        };
+        record("date", self.date.as_ref().map(|v| v.source));
+        record("make", self.make.as_ref().map(|v| v.source));
+        record("model", self.model.as_ref().map(|v| v.source));
+        record("lens", self.lens.as_ref().map(|v| v.source));
+        record("serial", self.serial.as_ref().map(|v| v.source));
+        record("iso", self.iso.as_ref().map(|v| v.source));
+        record("shutter", self.shutter.as_ref().map(|v| v.source));
+        record("aperture", self.aperture.as_ref().map(|v| v.source));
+        record("focal_length", self.focal_length.as_ref().map(|v| v.source));
+        serde_json::to_string(&sources).unwrap_or_default()
+    }
+}
+
+fn pick<T>(exif: Option<T>, libraw: Option<T>) -> Option<Sourced<T>> {
+    exif.map(|value| Sourced { value, source: MetadataSource::Exif })
+        .or_else(|| libraw.map(|value| Sourced { value, source: MetadataSource::Libraw }))
+}
+
+fn exif_string(exif: &Metadata, tag: &str) -> Option<String> {
+    exif.get_tag_string(tag)
+        .ok()
+        .map(|val| val.replace(['"', ','], "").trim().to_string())
+        .filter(|val| !val.is_empty())
+}
+
+fn c_string(field: &[c_char]) -> Option<String> {
+    let bytes: Vec<u8> = field.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+    Some(String::from_utf8_lossy(&bytes).trim().to_string()).filter(|val| !val.is_empty())
+}
+
+fn positive(value: f32) -> Option<f64> {
+    (value > 0.0).then_some(value as f64)
+}
+
+/// libraw builds its timestamp from the camera's wall time with `mktime`, so it is converted
+/// back to that wall time. The camera's offset is unknown.
+fn libraw_timestamp(timestamp: i64) -> Option<CaptureTime> {
+    (timestamp > 0)
+        .then(|| Local.timestamp_opt(timestamp, 0).single())
+        .flatten()
+        .map(|d| CaptureTime::new(d.naive_local(), None))
+}
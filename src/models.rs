@@ -1,16 +1,34 @@
 use std::hash::Hash;
+use std::path::Path;
 
 use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, Selectable, Insertable)]
+use crate::dates::CaptureTime;
+use crate::transfer::LinkMode;
+
+#[derive(Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::photos)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct Photo {
     pub hash: i64,
     pub original_path: String,
     pub current_path: String,
-    pub exif_date: i64,
     pub exif_json: String,
+    pub exif_date: i64,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens: Option<String>,
+    pub serial: Option<String>,
+    pub iso: Option<i32>,
+    pub shutter: Option<f64>,
+    pub aperture: Option<f64>,
+    pub focal_length: Option<f64>,
+    pub metadata_sources: String,
+    pub date_source: Option<String>,
+    pub utc_offset: Option<i32>,
+    pub subsec_ms: Option<i32>,
+    pub link_type: String,
 }
 
 #[derive(Queryable, Selectable, Insertable)]
@@ -23,6 +41,31 @@ pub struct Duplicate {
     pub deleted: bool,
 }
 
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::scan_cache)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ScanEntry {
+    pub path: String,
+    pub size: i64,
+    pub mtime: i64,
+    pub inode: i64,
+    pub hash: i64,
+}
+
+impl Photo {
+    pub fn capture_time(&self) -> CaptureTime {
+        CaptureTime::from_db(self.exif_date, self.utc_offset, self.subsec_ms)
+    }
+
+    /// The row of this photo once it is stored at `path`, created with `link`.
+    pub fn placed_at(&self, path: &Path, link: LinkMode) -> Photo {
+        let mut row = self.clone();
+        row.current_path = path.to_string_lossy().to_string();
+        row.link_type = link.as_str().to_string();
+        row
+    }
+}
+
 impl Eq for Photo {}
 
 impl PartialEq for Photo {
@@ -0,0 +1,507 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::models::Photo;
+
+/// The layout used before templates were configurable: `root/year/month/model/filename`.
+pub const DEFAULT_TEMPLATE: &str = "{year}/{month}/{model}/{filename}";
+
+/// Characters that are not allowed in a path component on at least one supported platform.
+const ILLEGAL_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Upper bound on `{seq}` numbers tried before giving up on finding an unused one.
+const MAX_SEQ: u32 = 999_999;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Make,
+    Model,
+    Lens,
+    Serial,
+    Iso,
+    Shutter,
+    Aperture,
+    FocalLength,
+    DateSource,
+    Hash,
+    Seq,
+    Date,
+    Subsec,
+    Filename,
+    Stem,
+    Ext,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "year" => Some(Field::Year),
+            "month" => Some(Field::Month),
+            "day" => Some(Field::Day),
+            "hour" => Some(Field::Hour),
+            "minute" => Some(Field::Minute),
+            "second" => Some(Field::Second),
+            "make" => Some(Field::Make),
+            "model" => Some(Field::Model),
+            "lens" => Some(Field::Lens),
+            "serial" => Some(Field::Serial),
+            "iso" => Some(Field::Iso),
+            "shutter" => Some(Field::Shutter),
+            "aperture" => Some(Field::Aperture),
+            "focal_length" => Some(Field::FocalLength),
+            "date_source" => Some(Field::DateSource),
+            "hash" => Some(Field::Hash),
+            "seq" => Some(Field::Seq),
+            "date" => Some(Field::Date),
+            "subsec" => Some(Field::Subsec),
+            "filename" => Some(Field::Filename),
+            "stem" => Some(Field::Stem),
+            "ext" => Some(Field::Ext),
+            _ => None,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(
+            self,
+            Field::Year
+                | Field::Month
+                | Field::Day
+                | Field::Hour
+                | Field::Minute
+                | Field::Second
+                | Field::Iso
+                | Field::Seq
+                | Field::Subsec
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Filter {
+    Slug,
+    Lower,
+    Upper,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Token { field: Field, width: Option<usize>, format: Option<String>, filters: Vec<Filter> },
+}
+
+/// A library layout such as `{year}/{month:02}/{year}-{month:02}-{day:02}/{model|slug}/{filename}`.
+///
+/// `{field:N}` zero-pads numbers to N digits, keeps the first N hex digits of `{hash}` and
+/// truncates text to N characters. `{date:FORMAT}` formats the capture time with a strftime
+/// format. `|slug`, `|lower` and `|upper` transform the value. Every rendered component is
+/// sanitized, and folders that render empty are dropped. A file name that renders empty becomes
+/// `{hash}.{ext}`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathTemplate {
+    source: String,
+    components: Vec<Vec<Segment>>,
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        PathTemplate::parse(&template)
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(template: PathTemplate) -> Self {
+        template.source
+    }
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        PathTemplate::parse(DEFAULT_TEMPLATE).unwrap()
+    }
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        if template.starts_with('/') || template.starts_with('\\') {
+            return Err(format!("template '{}' must be relative to the database root", template));
+        }
+        let components = template
+            .split('/')
+            .map(|component| match component {
+                "" | "." | ".." => Err(format!("invalid path component '{}'", component)),
+                _ => parse_component(component),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let last = components.last().unwrap();
+        if !last.iter().any(|s| matches!(s, Segment::Token { .. })) {
+            return Err(format!("the file name in template '{}' has no fields", template));
+        }
+        Ok(PathTemplate { source: template.to_string(), components })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the template is a single file name, as rename patterns must be.
+    pub fn is_file_name(&self) -> bool {
+        self.components.len() == 1
+    }
+
+    /// Uses the file name pattern `rename` in place of `{filename}`, or as the whole file name
+    /// when the layout does not use `{filename}`.
+    pub fn with_rename(&self, rename: &PathTemplate) -> PathTemplate {
+        let mut components = self.components.clone();
+        let name = rename.components.concat();
+        let last = components.last_mut().unwrap();
+        if last.iter().any(|s| matches!(s, Segment::Token { field: Field::Filename, .. })) {
+            *last = last
+                .iter()
+                .flat_map(|segment| match segment {
+                    Segment::Token { field: Field::Filename, .. } => name.clone(),
+                    other => vec![other.clone()],
+                })
+                .collect();
+        } else {
+            *last = name;
+        }
+        PathTemplate { source: self.source.clone(), components }
+    }
+
+    /// Whether the template numbers photos with `{seq}`.
+    pub fn uses_seq(&self) -> bool {
+        self.components
+            .iter()
+            .flatten()
+            .any(|s| matches!(s, Segment::Token { field: Field::Seq, .. }))
+    }
+
+    /// The location of `photo` under `db_root`, numbered `seq` if the template uses `{seq}`.
+    pub fn render(&self, db_root: &Path, photo: &Photo, seq: u32) -> PathBuf {
+        let mut path = db_root.to_path_buf();
+        let (name, folders) = self.components.split_last().unwrap();
+        for component in folders.iter() {
+            let rendered = render_component(component, photo, seq);
+            if !rendered.is_empty() {
+                path.push(rendered);
+            }
+        }
+        let name = render_component(name, photo, seq);
+        match name.is_empty() {
+            true => path.push(fallback_file_name(photo)),
+            false => path.push(name),
+        }
+        path
+    }
+
+    /// Renders `photo` with the lowest `{seq}` whose path is neither on disk nor in `taken`.
+    /// `current` is the photo's own location, which it may keep.
+    pub fn render_unused(
+        &self, db_root: &Path, photo: &Photo, current: Option<&Path>, taken: &HashSet<PathBuf>,
+    ) -> PathBuf {
+        match self.uses_seq() {
+            true => (1..=MAX_SEQ)
+                .map(|seq| self.render(db_root, photo, seq))
+                .find(|path| {
+                    !taken.contains(path) && (!path.exists() || Some(path.as_path()) == current)
+                })
+                .unwrap_or_else(|| self.render(db_root, photo, MAX_SEQ)),
+            false => self.render(db_root, photo, 1),
+        }
+    }
+}
+
+fn parse_component(component: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = component;
+    while !rest.is_empty() {
+        match rest.find(['{', '}']) {
+            Some(0) if rest.starts_with('{') => {
+                let end =
+                    rest.find('}').ok_or_else(|| format!("unclosed '{{' in '{}'", component))?;
+                segments.push(parse_token(&rest[1..end])?);
+                rest = &rest[end + 1..];
+            }
+            Some(0) => return Err(format!("unmatched '}}' in '{}'", component)),
+            Some(i) => {
+                segments.push(parse_literal(&rest[..i])?);
+                rest = &rest[i..];
+            }
+            None => {
+                segments.push(parse_literal(rest)?);
+                rest = "";
+            }
+        }
+    }
+    Ok(segments)
+}
+
+fn parse_literal(literal: &str) -> Result<Segment, String> {
+    match literal.chars().find(|c| ILLEGAL_CHARS.contains(c) || c.is_control()) {
+        Some(c) => Err(format!("'{}' is not allowed in a file name", c)),
+        None => Ok(Segment::Literal(literal.to_string())),
+    }
+}
+
+fn parse_token(token: &str) -> Result<Segment, String> {
+    let mut parts = token.split('|');
+    let head = parts.next().unwrap();
+    let (name, spec) = match head.split_once(':') {
+        Some((name, spec)) => (name, Some(spec)),
+        None => (head, None),
+    };
+    let field = Field::parse(name.trim()).ok_or_else(|| format!("unknown field '{}'", name))?;
+    let (width, format) = match (field, spec) {
+        (Field::Date, Some(format)) => {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(format!("invalid date format in '{{{}}}'", token));
+            }
+            (None, Some(format.to_string()))
+        }
+        (_, Some(width)) => {
+            let width =
+                width.parse::<usize>().map_err(|_| format!("invalid width in '{{{}}}'", token))?;
+            (Some(width), None)
+        }
+        (_, None) => (None, None),
+    };
+    let filters = parts
+        .map(|filter| match filter.trim() {
+            "slug" => Ok(Filter::Slug),
+            "lower" => Ok(Filter::Lower),
+            "upper" => Ok(Filter::Upper),
+            other => Err(format!("unknown filter '{}'", other)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Segment::Token { field, width, format, filters })
+}
+
+fn render_component(component: &[Segment], photo: &Photo, seq: u32) -> String {
+    let rendered: String =
+        component.iter().map(|segment| render_segment(segment, photo, seq)).collect();
+    trim_component(&rendered)
+}
+
+/// The file name of photos whose template renders an empty one, e.g. `{lens}` without a lens:
+/// `{hash}.{ext}`, which is unique in the library.
+fn fallback_file_name(photo: &Photo) -> String {
+    let hash = field_value(Field::Hash, photo, 1);
+    match sanitize(&field_value(Field::Ext, photo, 1)) {
+        ext if ext.is_empty() => hash,
+        ext => format!("{}.{}", hash, ext),
+    }
+}
+
+fn render_segment(segment: &Segment, photo: &Photo, seq: u32) -> String {
+    match segment {
+        Segment::Literal(literal) => literal.clone(),
+        Segment::Token { field, width, format, filters } => {
+            let value = match format {
+                Some(format) => {
+                    capture_time(photo).map_or(String::new(), |t| t.format(format).to_string())
+                }
+                None => field_value(*field, photo, seq),
+            };
+            let value = match (width, field) {
+                (Some(width), Field::Hash) => value.chars().take(*width).collect(),
+                (Some(width), f) if f.is_number() => format!("{:0>width$}", value, width = width),
+                (Some(width), _) => value.chars().take(*width).collect(),
+                (None, _) => value,
+            };
+            let value = filters.iter().fold(value, |value, filter| match filter {
+                Filter::Slug => slug(&value),
+                Filter::Lower => value.to_lowercase(),
+                Filter::Upper => value.to_uppercase(),
+            });
+            sanitize(&value)
+        }
+    }
+}
+
+/// Rows without any date source were stored with a zero date, and are filed under 0/0.
+fn capture_time(photo: &Photo) -> Option<NaiveDateTime> {
+    (photo.exif_date != 0 || photo.date_source.is_some()).then(|| photo.capture_time().local)
+}
+
+fn field_value(field: Field, photo: &Photo, seq: u32) -> String {
+    let time = capture_time(photo);
+    let date_part = |f: fn(&NaiveDateTime) -> u32| time.as_ref().map_or(0, f).to_string();
+    let original = Path::new(&photo.original_path);
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    let number = |value: Option<f64>| value.map(format_number).unwrap_or_default();
+    match field {
+        Field::Year => time.map_or(0, |t| t.year()).to_string(),
+        Field::Month => date_part(|t| t.month()),
+        Field::Day => date_part(|t| t.day()),
+        Field::Hour => date_part(|t| t.hour()),
+        Field::Minute => date_part(|t| t.minute()),
+        Field::Second => date_part(|t| t.second()),
+        Field::Make => text(&photo.camera_make),
+        Field::Model => text(&photo.camera_model),
+        Field::Lens => text(&photo.lens),
+        Field::Serial => text(&photo.serial),
+        Field::Iso => photo.iso.map(|iso| iso.to_string()).unwrap_or_default(),
+        Field::Shutter => match photo.shutter {
+            Some(shutter) if shutter > 0.0 && shutter < 1.0 => {
+                format!("1-{}", (1.0 / shutter).round())
+            }
+            shutter => number(shutter),
+        },
+        Field::Aperture => number(photo.aperture),
+        Field::FocalLength => number(photo.focal_length),
+        Field::DateSource => text(&photo.date_source),
+        Field::Hash => format!("{:016x}", photo.hash),
+        Field::Seq => seq.to_string(),
+        Field::Date => time.map(|t| t.format("%Y-%m-%d").to_string()).unwrap_or_default(),
+        Field::Subsec => format!("{:03}", photo.subsec_ms.unwrap_or(0)),
+        Field::Filename => os_text(original.file_name()),
+        Field::Stem => os_text(original.file_stem()),
+        Field::Ext => os_text(original.extension()),
+    }
+}
+
+fn os_text(value: Option<&OsStr>) -> String {
+    value.map(|v| v.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+/// Numbers with at most one decimal, without a trailing `.0`.
+fn format_number(value: f64) -> String {
+    let rounded = (value * 10.0).round() / 10.0;
+    format!("{}", rounded)
+}
+
+fn slug(value: &str) -> String {
+    let mut slug = String::with_capacity(value.len());
+    for c in value.to_lowercase().chars() {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+/// Replaces characters that cannot appear in a file name.
+pub fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if ILLEGAL_CHARS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+/// Windows rejects components ending in a dot or a space. This also turns `.` and `..` into
+/// empty components, which are dropped.
+fn trim_component(component: &str) -> String {
+    component.trim().trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row() -> Photo {
+        Photo {
+            hash: 0x0123_4567_89ab_cdef,
+            original_path: "/card/DCIM/DSC0001.ARW".to_string(),
+            current_path: String::new(),
+            exif_json: "{}".to_string(),
+            // 2024-06-05 07:08:09 UTC
+            exif_date: 1_717_571_289,
+            camera_make: Some("SONY".to_string()),
+            camera_model: Some("ILCE-7M3".to_string()),
+            lens: None,
+            serial: None,
+            iso: Some(100),
+            shutter: Some(0.004),
+            aperture: Some(2.8),
+            focal_length: Some(35.0),
+            metadata_sources: "{}".to_string(),
+            date_source: Some("exif".to_string()),
+            utc_offset: None,
+            subsec_ms: None,
+            link_type: "copy".to_string(),
+        }
+    }
+
+    fn render(template: &str, photo: &Photo) -> PathBuf {
+        let template = PathTemplate::parse(template).unwrap();
+        template.render(Path::new("/lib"), photo, 7).strip_prefix("/lib").unwrap().to_path_buf()
+    }
+
+    #[test]
+    fn renders_the_default_layout() {
+        assert_eq!(render(DEFAULT_TEMPLATE, &row()), Path::new("2024/6/ILCE-7M3/DSC0001.ARW"));
+    }
+
+    #[test]
+    fn renders_fields() {
+        let template = "{date}/{make}_{iso}_{shutter}_{aperture}_{focal_length}/{stem}-{seq}.{ext}";
+        assert_eq!(
+            render(template, &row()),
+            Path::new("2024-06-05/SONY_100_1-250_2.8_35/DSC0001-7.ARW")
+        );
+        assert_eq!(render("{date:%Y%m%d_%H%M%S}.{ext}", &row()), Path::new("20240605_070809.ARW"));
+    }
+
+    #[test]
+    fn applies_widths() {
+        assert_eq!(
+            render("{month:02}/{day:03}/{seq:4}-{hash:8}", &row()),
+            Path::new("06/005/0007-01234567")
+        );
+        assert_eq!(render("{model:4}/{filename}", &row()), Path::new("ILCE/DSC0001.ARW"));
+    }
+
+    #[test]
+    fn applies_filters() {
+        let mut photo = row();
+        photo.lens = Some("FE 35mm F1.8".to_string());
+        assert_eq!(
+            render("{lens|slug}/{model|lower}/{stem|upper}", &photo),
+            Path::new("fe-35mm-f1-8/ilce-7m3/DSC0001")
+        );
+        assert_eq!(render("{lens:5|slug}/{filename}", &photo), Path::new("fe-35/DSC0001.ARW"));
+    }
+
+    #[test]
+    fn sanitizes_and_skips_empty_folders() {
+        let mut photo = row();
+        photo.camera_model = Some("A/B: C.".to_string());
+        assert_eq!(render("{model}/{filename}", &photo), Path::new("A_B_ C/DSC0001.ARW"));
+        assert_eq!(render("{year}/{lens}/{filename}", &row()), Path::new("2024/DSC0001.ARW"));
+    }
+
+    #[test]
+    fn falls_back_to_the_hash_for_empty_file_names() {
+        assert_eq!(render("{year}/{lens}", &row()), Path::new("2024/0123456789abcdef.ARW"));
+        let mut photo = row();
+        photo.original_path = "/card/DSC0001".to_string();
+        assert_eq!(render("{year}/{ext}", &photo), Path::new("2024/0123456789abcdef"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(PathTemplate::parse("/{year}/{filename}").is_err());
+        assert!(PathTemplate::parse("{year}//{filename}").is_err());
+        assert!(PathTemplate::parse("{year}/photo.arw").is_err());
+        assert!(PathTemplate::parse("{year}/{nope}").is_err());
+        assert!(PathTemplate::parse("{year}/{filename|nope}").is_err());
+        assert!(PathTemplate::parse("{year}/{month:x}").is_err());
+        assert!(PathTemplate::parse("{year}/{filename").is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use std::{error::Error, fmt, path::PathBuf};
+use std::{
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Clone)]
 pub struct PhotoDBError {
@@ -7,9 +11,17 @@ pub struct PhotoDBError {
 }
 
 impl PhotoDBError {
-    pub fn new(msg: &str, path: &PathBuf) -> PhotoDBError {
+    pub fn new(msg: &str, path: &Path) -> PhotoDBError {
         PhotoDBError { details: msg.to_string(), path: path.to_path_buf() }
     }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn details(&self) -> &str {
+        &self.details
+    }
 }
 
 impl fmt::Display for PhotoDBError {
@@ -23,3 +35,15 @@ impl Error for PhotoDBError {
         &self.details
     }
 }
+
+impl From<diesel::result::Error> for PhotoDBError {
+    fn from(e: diesel::result::Error) -> Self {
+        PhotoDBError::new(format!("database: {}", e).as_str(), &PathBuf::new())
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for PhotoDBError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        PhotoDBError::new(format!("database connection: {}", e).as_str(), &PathBuf::new())
+    }
+}
@@ -0,0 +1,258 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::companions::{companion_destination, find_companions};
+use crate::config::{LibraryConfig, Overrides};
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::models::{Photo, ScanEntry};
+use crate::photodb_error::PhotoDBError;
+use crate::scan_cache::stat_entry;
+use crate::transfer::{self, LinkMode};
+
+/// Version of the plan file format.
+const PLAN_VERSION: u32 = 1;
+
+/// The exact operations a mutating command would run, written to a file so they can be reviewed
+/// and then executed by `photodb apply`.
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    /// The command that planned the operations, e.g. `import`, `sync` or `clean`.
+    pub command: String,
+    /// The library the operations write to.
+    pub db_root: PathBuf,
+    pub xattrs: bool,
+    pub operations: Vec<Operation>,
+}
+
+/// One operation of a plan, with the state of the files it expects to find.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Copy or link `source` and its companions to `row.current_path`, using `row.link_type`,
+    /// then commit `row`.
+    Place { source: PathBuf, size: i64, mtime: i64, row: Box<Photo> },
+    /// Delete `path` and its companions once `copy` is verified to hold the same pixels.
+    DeleteSource { path: PathBuf, size: i64, mtime: i64, hash: i64, copy: PathBuf },
+}
+
+impl Plan {
+    pub fn new(command: &str, db_root: &Path, xattrs: bool) -> Self {
+        Plan {
+            version: PLAN_VERSION,
+            command: command.to_string(),
+            db_root: db_root.to_path_buf(),
+            xattrs,
+            operations: Vec::new(),
+        }
+    }
+
+    /// Plans placing `source` as `row`.
+    pub fn place(&mut self, source: &PathBuf, row: Photo) -> Result<(), PhotoDBError> {
+        let stat = stat_source(source)?;
+        self.operations.push(Operation::Place {
+            source: source.to_path_buf(),
+            size: stat.size,
+            mtime: stat.mtime,
+            row: Box::new(row),
+        });
+        Ok(())
+    }
+
+    /// Plans deleting `path` once `copy` is verified to have `hash`.
+    pub fn delete_source(
+        &mut self, path: &PathBuf, hash: i64, copy: &Path,
+    ) -> Result<(), PhotoDBError> {
+        let stat = stat_source(path)?;
+        self.operations.push(Operation::DeleteSource {
+            path: path.to_path_buf(),
+            size: stat.size,
+            mtime: stat.mtime,
+            hash,
+            copy: copy.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    pub fn write(&self, path: &PathBuf) -> Result<(), PhotoDBError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| PhotoDBError::new(format!("writing plan: {}", e).as_str(), path))?;
+        fs::write(path, json)
+            .map_err(|e| PhotoDBError::new(format!("writing plan: {}", e).as_str(), path))
+    }
+
+    pub fn read(path: &PathBuf) -> Result<Self, PhotoDBError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| PhotoDBError::new(format!("reading plan: {}", e).as_str(), path))?;
+        let plan: Plan = serde_json::from_str(&contents)
+            .map_err(|e| PhotoDBError::new(format!("reading plan: {}", e).as_str(), path))?;
+        if plan.version != PLAN_VERSION {
+            let message = format!("unsupported plan version {}", plan.version);
+            return Err(PhotoDBError::new(message.as_str(), path));
+        }
+        Ok(plan)
+    }
+}
+
+fn stat_source(path: &PathBuf) -> Result<ScanEntry, PhotoDBError> {
+    stat_entry(path).map_err(|e| PhotoDBError::new(format!("reading file: {}", e).as_str(), path))
+}
+
+fn check_unchanged(path: &PathBuf, size: i64, mtime: i64) -> Result<(), PhotoDBError> {
+    let stat = stat_source(path)?;
+    match stat.size == size && stat.mtime == mtime {
+        true => Ok(()),
+        false => Err(PhotoDBError::new("changed since the plan was made", path)),
+    }
+}
+
+/// Everything that changed since `plan` was made: sources that were modified or removed,
+/// destinations that were taken and photos that were imported in the meantime.
+pub fn check_plan(
+    plan: &Plan, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Vec<PhotoDBError> {
+    plan.operations
+        .iter()
+        .filter_map(|operation| match operation {
+            Operation::Place { source, size, mtime, row } => {
+                let dest = PathBuf::from(&row.current_path);
+                check_unchanged(source, *size, *mtime)
+                    .and_then(|_| match dest.exists() {
+                        true => Err(PhotoDBError::new("destination already exists", &dest)),
+                        false => Ok(()),
+                    })
+                    .and_then(|_| match db::is_imported(row.hash, pool)? {
+                        true => Err(PhotoDBError::new("already imported", source)),
+                        false => Ok(()),
+                    })
+                    .err()
+            }
+            Operation::DeleteSource { path, size, mtime, copy, .. } => {
+                check_unchanged(path, *size, *mtime)
+                    .and_then(|_| match copy.exists() {
+                        true => Ok(()),
+                        // The copy may come from a place operation earlier in the plan.
+                        false if plans_place(plan, copy) => Ok(()),
+                        false => Err(PhotoDBError::new("library copy is missing", copy)),
+                    })
+                    .err()
+            }
+        })
+        .collect()
+}
+
+fn plans_place(plan: &Plan, dest: &PathBuf) -> bool {
+    plan.operations.iter().any(|operation| match operation {
+        Operation::Place { row, .. } => Path::new(&row.current_path) == dest,
+        _ => false,
+    })
+}
+
+/// Runs the operations of a checked plan in order. Copies are verified against the planned hash
+/// before their row is committed, and sources are only deleted once their copy is re-hashed.
+/// Returns the number of operations that succeeded.
+pub fn apply_plan(plan: &Plan, pool: &Pool<ConnectionManager<SqliteConnection>>) -> usize {
+    let _temp_lock = transfer::lock_temp_files(&plan.db_root);
+    plan.operations
+        .iter()
+        .filter(|operation| match operation {
+            Operation::Place { source, row, .. } => place(source, row, plan.xattrs, pool),
+            Operation::DeleteSource { path, hash, copy, .. } => delete_source(path, *hash, copy),
+        })
+        .count()
+}
+
+fn place(
+    source: &Path, row: &Photo, xattrs: bool, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> bool {
+    let dest = PathBuf::from(&row.current_path);
+    let copied = dest
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| {
+            let link = LinkMode::from_db(&row.link_type);
+            transfer::link_or_copy(source, &dest, link, Some(row.hash), xattrs)
+        })
+        .map_err(|e| events::emit(Event::error(Category::Copy, source, format!("copying: {}", e))));
+    let Ok(used) = copied else { return false };
+    events::emit(Event::copied(source, &dest, used, false));
+    for companion in find_companions(source) {
+        let companion_dest = companion_destination(&companion, source, &dest);
+        match transfer::link_or_copy(&companion, &companion_dest, used, None, xattrs) {
+            Ok(_) => events::emit(Event::copied(&companion, &companion_dest, used, false)),
+            Err(e) => {
+                events::emit(Event::error(Category::Copy, &companion, format!("copying: {}", e)))
+            }
+        }
+    }
+    let row = row.placed_at(&dest, used);
+    match db::insert_file_to_db(&row, pool) {
+        Ok(_) => {
+            events::emit(Event::inserted(source, &dest, row.hash, false));
+            true
+        }
+        Err(e) => {
+            events::emit(Event::error(
+                Category::Insert,
+                source,
+                format!("inserting file: {}", e.details()),
+            ));
+            for companion in find_companions(source) {
+                fs::remove_file(companion_destination(&companion, source, &dest)).ok();
+            }
+            fs::remove_file(&dest).ok();
+            false
+        }
+    }
+}
+
+fn delete_source(path: &PathBuf, hash: i64, copy: &PathBuf) -> bool {
+    let deleted = transfer::delete_verified_source(path, copy, hash).map(|_| path.clone());
+    std::iter::once(deleted)
+        .chain(find_companions(path).into_iter().map(|companion| {
+            let dest = companion_destination(&companion, path, copy);
+            transfer::delete_verified_companion(&companion, &dest).map(|_| companion)
+        }))
+        .map(|result| match result {
+            Ok(deleted) => {
+                events::emit(Event::Deleted { path: events::path(&deleted), dry_run: false });
+                true
+            }
+            Err(e) => {
+                events::emit(Event::failed(Category::Delete, &e));
+                false
+            }
+        })
+        // Every file is tried, even after one could not be deleted.
+        .collect::<Vec<bool>>()
+        .into_iter()
+        .all(|deleted| deleted)
+}
+
+/// Runs a plan after checking that none of its files changed since it was written, with the
+/// settings of the library it was written for.
+pub fn apply_plan_file(plan_path: &PathBuf, overrides: &Overrides) {
+    let plan =
+        Plan::read(plan_path).unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let config = LibraryConfig::resolve(&plan.db_root, overrides)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    config.apply_limits();
+    let database = db::get_connection_pool_for(&config.database_path(&plan.db_root))
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let changed = check_plan(&plan, &database);
+    if !changed.is_empty() {
+        for e in changed.iter() {
+            events::emit(Event::failed(Category::Input, e));
+        }
+        let message = format!("{} operations no longer match, plan again", changed.len());
+        events::fatal(Event::error(Category::Input, plan_path, message));
+    }
+    events::note(format!("Applying {} {} operations", plan.operations.len(), plan.command));
+    let applied = apply_plan(&plan, &database);
+    events::note(format!("Applied {}/{} operations", applied, plan.operations.len()));
+    events::summary("apply", vec![("operations", plan.operations.len()), ("applied", applied)]);
+}
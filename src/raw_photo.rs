@@ -8,12 +8,17 @@ use libraw_rs_vendor::{
     LibRaw_errors_LIBRAW_SUCCESS,
 };
 use rexiv2::Metadata;
+use std::collections::BTreeMap;
+use std::fs;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use xxhash_rust::xxh3::Xxh3;
 
+use crate::metadata::PhotoMetadata;
+use crate::models;
+use crate::path_template::PathTemplate;
 use crate::photodb_error::PhotoDBError;
-use crate::util::{build_final_path, get_date};
+use crate::transfer::LinkMode;
 const SEED: u64 = 0xdeadbeef;
 
 #[derive(Clone, Debug)]
@@ -22,9 +27,13 @@ pub struct Photo {
     pub year: i32,
     pub month: u32,
     pub model: String,
+    pub exif_date: i64,
+    pub exif_json: String,
+    pub metadata: PhotoMetadata,
     pub db_root: PathBuf,
     pub db_path: PathBuf,
     pub og_path: PathBuf,
+    pub link: LinkMode,
 }
 
 impl PartialEq for Photo {
@@ -42,59 +51,100 @@ impl Hash for Photo {
 }
 
 impl Photo {
-    pub fn new(buf: &[u8], og_path: &PathBuf, db_root: &PathBuf) -> Result<Self, PhotoDBError> {
+    /// Reads and hashes a raw. `db_path` is rendered from `template` with `{seq}` set to 1.
+    pub fn new(
+        buf: &[u8], og_path: &Path, db_root: &Path, template: &PathTemplate,
+    ) -> Result<Self, PhotoDBError> {
         let libraw_data = unsafe { libraw_init(0) };
         let raw_data = Self::read_raw_data(libraw_data, buf, og_path)?;
         let hash = Self::get_hash(&raw_data);
-        let model = Self::get_model(libraw_data);
         let exif = Self::get_exif(buf, og_path);
-        let exif_model = Self::get_exif_model(&exif);
-        let date_tuple = Self::get_date_tuple(&exif); //.unwrap()) } else {(0, 0)};
-        let final_model = if exif_model.is_empty() { model } else { exif_model };
-        let import_path_full =
-            build_final_path(db_root, &final_model, &date_tuple.0, &date_tuple.1, &og_path);
+        // read_raw_data succeeded, so libraw_data points to an opened file until it is closed.
+        let metadata = PhotoMetadata::read(&exif, unsafe { &*libraw_data }, og_path);
+        let date_tuple = Self::get_date_tuple(&metadata);
+        let exif_date = metadata.date.as_ref().map(|d| d.value.timestamp()).unwrap_or(0);
+        let exif_json = Self::get_exif_json(&exif);
+        let final_model = metadata.model.as_ref().map(|m| m.value.clone()).unwrap_or_default();
         unsafe { libraw_close(libraw_data) };
-        Ok(Self {
-            hash: hash,
+        let mut photo = Self {
+            hash,
             year: date_tuple.0,
             month: date_tuple.1,
             model: final_model,
+            exif_date,
+            exif_json,
+            metadata,
             db_root: db_root.to_path_buf(),
-            db_path: import_path_full.to_path_buf(),
+            db_path: PathBuf::new(),
             og_path: og_path.to_path_buf(),
-        })
+            link: LinkMode::Copy,
+        };
+        photo.db_path = template.render(db_root, &photo.to_db_row(), 1);
+        Ok(photo)
     }
 
-    fn get_exif_model(exif: &Result<Metadata, PhotoDBError>) -> String {
-        match exif {
-            Ok(exif) => {
-                let model = exif.get_tag_string("Exif.Image.Model");
-                match model {
-                    Ok(model) => model.replace("\"", "").replace(",", "").trim().to_string(),
-                    Err(_) => "".to_string(),
-                }
-            }
-            Err(_) => "".to_string(),
-        }
+    /// Hashes the pixel content of a raw without reading its metadata.
+    pub fn hash_file(path: &PathBuf) -> Result<i128, PhotoDBError> {
+        let buf = fs::read(path)
+            .map_err(|e| PhotoDBError::new(format!("reading file: {}", e).as_str(), path))?;
+        let libraw_data = unsafe { libraw_init(0) };
+        let raw_data = Self::read_raw_data(libraw_data, &buf, path);
+        unsafe { libraw_close(libraw_data) };
+        raw_data.map(|raw_data| Self::get_hash(&raw_data))
     }
 
-    fn get_date_tuple(exif_rs: &Result<Metadata, PhotoDBError>) -> (i32, u32) {
-        match exif_rs {
-            Ok(exif) => get_date(&exif).and_then(|d| Some((d.year(), d.month()))).unwrap_or((0, 0)),
-            Err(_) => (0, 0),
+    /// The row stored in the `photos` table for this photo.
+    pub fn to_db_row(&self) -> models::Photo {
+        models::Photo {
+            hash: self.hash as i64,
+            original_path: self.og_path.to_string_lossy().to_string(),
+            current_path: self.db_path.to_string_lossy().to_string(),
+            exif_json: self.exif_json.clone(),
+            exif_date: self.exif_date,
+            camera_make: self.metadata.make.as_ref().map(|v| v.value.clone()),
+            camera_model: self.metadata.model.as_ref().map(|v| v.value.clone()),
+            lens: self.metadata.lens.as_ref().map(|v| v.value.clone()),
+            serial: self.metadata.serial.as_ref().map(|v| v.value.clone()),
+            iso: self.metadata.iso.as_ref().map(|v| v.value),
+            shutter: self.metadata.shutter.as_ref().map(|v| v.value),
+            aperture: self.metadata.aperture.as_ref().map(|v| v.value),
+            focal_length: self.metadata.focal_length.as_ref().map(|v| v.value),
+            metadata_sources: self.metadata.sources_json(),
+            date_source: self.metadata.date.as_ref().map(|d| d.source.as_str().to_string()),
+            utc_offset: self.metadata.date.as_ref().and_then(|d| d.value.utc_offset()),
+            subsec_ms: self.metadata.date.as_ref().and_then(|d| d.value.subsec_ms()),
+            link_type: self.link.as_str().to_string(),
         }
     }
 
-    fn get_model(libraw_data: *mut libraw_data_t) -> String {
-        unsafe {
-            let make = (*libraw_data).idata.make.as_ptr() as *const u8;
-            let make_len = (*libraw_data).idata.make.len();
-            let make_slice = slice::from_raw_parts(make, make_len);
-            String::from_utf8_lossy(make_slice).to_string()
-        }
+    /// Folders follow the wall time where the photo was taken, not UTC.
+    fn get_date_tuple(metadata: &PhotoMetadata) -> (i32, u32) {
+        metadata
+            .date
+            .as_ref()
+            .map(|d| (d.value.local.year(), d.value.local.month()))
+            .unwrap_or((0, 0))
+    }
+
+    fn get_exif_json(exif_rs: &Result<Metadata, PhotoDBError>) -> String {
+        let tags: BTreeMap<String, String> = match exif_rs {
+            Ok(exif) => exif
+                .get_exif_tags()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|tag| {
+                    exif.get_tag_string(&tag)
+                        .ok()
+                        .filter(|val| val.len() <= 100)
+                        .map(|val| (tag, val))
+                })
+                .collect(),
+            Err(_) => BTreeMap::new(),
+        };
+        serde_json::to_string(&tags).unwrap_or_default()
     }
 
-    fn get_hash(raw_data: &Vec<u16>) -> i128 {
+    fn get_hash(raw_data: &[u16]) -> i128 {
         let mut xxh: Xxh3 = Xxh3::with_seed(SEED);
         for u16 in raw_data.iter() {
             xxh.update(&u16.to_le_bytes());
@@ -103,7 +153,7 @@ impl Photo {
     }
 
     fn read_raw_data(
-        libraw_data: *mut libraw_data_t, buf: &[u8], og_path: &PathBuf,
+        libraw_data: *mut libraw_data_t, buf: &[u8], og_path: &Path,
     ) -> Result<Vec<u16>, PhotoDBError> {
         match unsafe { libraw_open_buffer(libraw_data, buf.as_ptr() as *const _, buf.len()) } {
             LibRaw_errors_LIBRAW_SUCCESS => match unsafe { libraw_unpack(libraw_data) } {
@@ -125,8 +175,8 @@ impl Photo {
         }
     }
 
-    fn get_exif(buf: &[u8], og_path: &PathBuf) -> Result<rexiv2::Metadata, PhotoDBError> {
-        match rexiv2::Metadata::new_from_buffer(&buf) {
+    fn get_exif(buf: &[u8], og_path: &Path) -> Result<rexiv2::Metadata, PhotoDBError> {
+        match rexiv2::Metadata::new_from_buffer(buf) {
             Ok(exif) => Ok(exif),
             Err(e) => {
                 Err(PhotoDBError::new(format!("unable to read exif data: {}", e).as_str(), og_path))
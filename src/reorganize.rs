@@ -0,0 +1,177 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::companions::move_with_companions;
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::journal::{build_journal_path, Journal};
+use crate::models::Photo;
+use crate::path_template::PathTemplate;
+use crate::photodb_error::PhotoDBError;
+use crate::util::{self, collision_free_path};
+
+const JOURNAL_NAME: &str = "reorganize";
+
+/// A photo that is not where the library layout puts it.
+#[derive(Clone, Debug)]
+pub struct Move {
+    pub hash: i64,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Planned { hash: i64, from: PathBuf, to: PathBuf },
+    Moved { hash: i64 },
+}
+
+/// Computes where every photo belongs under the current layout, in capture order.
+pub fn plan_reorganize(
+    mut photos: Vec<Photo>, db_root: &Path, template: &PathTemplate,
+) -> Vec<Move> {
+    photos.sort_by(|a, b| (a.exif_date, &a.current_path).cmp(&(b.exif_date, &b.current_path)));
+    let mut taken = HashSet::new();
+    photos
+        .into_iter()
+        .filter_map(|photo| {
+            let current = PathBuf::from(&photo.current_path);
+            let rendered = template.render_unused(db_root, &photo, Some(&current), &taken);
+            let to = collision_free_path(&rendered, photo.hash, |p| {
+                p == current || (!p.exists() && !taken.contains(p))
+            });
+            taken.insert(to.clone());
+            (to != current).then_some(Move { hash: photo.hash, from: current, to })
+        })
+        .collect()
+}
+
+/// The moves of a reorganize that was interrupted before it finished, if there is one.
+pub fn pending_moves(db_root: &Path) -> Result<Option<Vec<Move>>, PhotoDBError> {
+    let records = Journal::read::<Record>(&build_journal_path(db_root, JOURNAL_NAME))?;
+    Ok(records.map(|records| {
+        let moved: HashSet<i64> = records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Moved { hash } => Some(*hash),
+                _ => None,
+            })
+            .collect();
+        records
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Planned { hash, from, to } if !moved.contains(&hash) => {
+                    Some(Move { hash, from, to })
+                }
+                _ => None,
+            })
+            .collect()
+    }))
+}
+
+/// Moves the files and updates `current_path`, journaling every step so an interrupted run can
+/// be finished by `resume`-ing it. Directories left empty are removed afterwards.
+pub fn apply_reorganize(
+    moves: &[Move], db_root: &PathBuf, resume: bool,
+    pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<usize, PhotoDBError> {
+    let mut journal = Journal::open(&build_journal_path(db_root, JOURNAL_NAME))?;
+    if !resume {
+        for mv in moves.iter() {
+            journal.append(&Record::Planned {
+                hash: mv.hash,
+                from: mv.from.clone(),
+                to: mv.to.clone(),
+            })?;
+        }
+    }
+    for mv in moves.iter() {
+        move_with_companions(&mv.from, &mv.to, &mut Vec::new())?;
+        db::update_current_path(mv.hash, &mv.to, pool)?;
+        journal.append(&Record::Moved { hash: mv.hash })?;
+        events::emit(Event::Moved {
+            from: events::path(&mv.from),
+            to: events::path(&mv.to),
+            dry_run: false,
+        });
+    }
+    let dirs: BTreeSet<&Path> = moves.iter().filter_map(|mv| mv.from.parent()).collect();
+    for dir in dirs.into_iter().rev() {
+        remove_empty_dirs(dir, db_root);
+    }
+    journal.finish()?;
+    Ok(moves.len())
+}
+
+/// Removes `dir` and its parents up to `db_root` for as long as they are empty.
+fn remove_empty_dirs(dir: &Path, db_root: &PathBuf) {
+    let mut dir = Some(dir);
+    while let Some(current) = dir {
+        if current == db_root.as_path() || !current.starts_with(db_root) {
+            break;
+        }
+        // remove_dir fails on directories that are not empty.
+        if fs::remove_dir(current).is_err() {
+            break;
+        }
+        events::emit(Event::Deleted { path: events::path(current), dry_run: false });
+        dir = current.parent();
+    }
+}
+
+/// Moves every photo that is not where the layout puts it, after printing the moves and asking,
+/// unless `yes` is set. An interrupted reorganize is finished first.
+pub fn reorganize_library(
+    db_root: &PathBuf, yes: bool, database: &Pool<ConnectionManager<SqliteConnection>>,
+    template: &PathTemplate,
+) {
+    let pending = pending_moves(db_root)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let resume = pending.is_some();
+    let moves = match pending {
+        Some(moves) => {
+            events::note(format!(
+                "Resuming an interrupted reorganize with {} files left",
+                moves.len()
+            ));
+            moves
+        }
+        None => {
+            let photos: Vec<_> = db::get_photos(database)
+                .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)))
+                .into_iter()
+                .collect();
+            plan_reorganize(photos, db_root, template)
+        }
+    };
+    for mv in moves.iter() {
+        events::emit(Event::Moved {
+            from: events::path(&mv.from),
+            to: events::path(&mv.to),
+            dry_run: true,
+        });
+    }
+    if moves.is_empty() && !resume {
+        events::note("Every photo is already in place");
+        return;
+    }
+    if !yes && !util::confirm(format!("Move {} files?", moves.len()).as_str()) {
+        events::note("No files were moved");
+        return;
+    }
+    match apply_reorganize(&moves, db_root, resume, database) {
+        Ok(moved) => {
+            events::note(format!("Moved {}/{} files", moved, moves.len()));
+            events::summary("reorganize", vec![("planned", moves.len()), ("moved", moved)]);
+        }
+        Err(e) => {
+            events::note("Run reorganize again to resume");
+            events::fatal(Event::failed(Category::Move, &e));
+        }
+    }
+}
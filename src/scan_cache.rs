@@ -0,0 +1,75 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use filetime::FileTime;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::models::ScanEntry;
+use crate::photodb_error::PhotoDBError;
+
+/// Reads the size, modification time (in nanoseconds) and inode that identify an unchanged file.
+/// The cached hash is left at 0.
+pub fn stat_entry(path: &PathBuf) -> Result<ScanEntry, io::Error> {
+    let meta = fs::metadata(path)?;
+    let mtime = FileTime::from_last_modification_time(&meta);
+    Ok(ScanEntry {
+        path: path.to_string_lossy().to_string(),
+        size: meta.len() as i64,
+        mtime: mtime.unix_seconds() * 1_000_000_000 + mtime.nanoseconds() as i64,
+        inode: inode(&meta),
+        hash: 0,
+    })
+}
+
+#[cfg(unix)]
+fn inode(meta: &fs::Metadata) -> i64 {
+    std::os::unix::fs::MetadataExt::ino(meta) as i64
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &fs::Metadata) -> i64 {
+    0
+}
+
+/// The hash recorded for `path` when it was last hashed, if the file has not changed since.
+pub fn cached_hash(
+    path: &PathBuf, pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<Option<i64>, PhotoDBError> {
+    let Ok(current) = stat_entry(path) else { return Ok(None) };
+    Ok(db::get_scan_entry(path, pool)?
+        .filter(|cached| is_unchanged(cached, &current))
+        .map(|cached| cached.hash))
+}
+
+fn is_unchanged(before: &ScanEntry, after: &ScanEntry) -> bool {
+    before.size == after.size && before.mtime == after.mtime && before.inode == after.inode
+}
+
+/// Stats each of `paths` before it is hashed. Files that cannot be read are left out.
+pub fn stat_before_hashing(paths: &[PathBuf]) -> HashMap<PathBuf, ScanEntry> {
+    paths.iter().filter_map(|path| Some((path.clone(), stat_entry(path).ok()?))).collect()
+}
+
+/// Remembers the hashes of `hashed`, entries stat'ed before their file was hashed. Files that
+/// changed while they were hashed, or can no longer be read, are skipped.
+pub fn record(hashed: Vec<ScanEntry>, pool: &Pool<ConnectionManager<SqliteConnection>>) {
+    let entries: Vec<ScanEntry> = hashed
+        .into_iter()
+        .filter(|before| {
+            stat_entry(&PathBuf::from(&before.path)).is_ok_and(|after| is_unchanged(before, &after))
+        })
+        .collect();
+    db::upsert_scan_entries(&entries, pool)
+        .map_err(|e| {
+            events::emit(Event::error(
+                Category::Insert,
+                Path::new(""),
+                format!("updating scan cache: {}", e.details()),
+            ))
+        })
+        .ok();
+}
@@ -16,10 +16,30 @@ diesel::table! {
         current_path -> Text,
         exif_json -> Text,
         exif_date -> BigInt,
+        camera_make -> Nullable<Text>,
+        camera_model -> Nullable<Text>,
+        lens -> Nullable<Text>,
+        serial -> Nullable<Text>,
+        iso -> Nullable<Integer>,
+        shutter -> Nullable<Double>,
+        aperture -> Nullable<Double>,
+        focal_length -> Nullable<Double>,
+        metadata_sources -> Text,
+        date_source -> Nullable<Text>,
+        utc_offset -> Nullable<Integer>,
+        subsec_ms -> Nullable<Integer>,
+        link_type -> Text,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(
-    duplicates,
-    photos,
-);
+diesel::table! {
+    scan_cache (path) {
+        path -> Text,
+        size -> BigInt,
+        mtime -> BigInt,
+        inode -> BigInt,
+        hash -> BigInt,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(duplicates, photos, scan_cache,);
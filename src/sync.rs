@@ -0,0 +1,146 @@
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::LibraryConfig;
+use crate::db::{self, get_photos};
+use crate::events::{self, Category, Event};
+use crate::models::Photo;
+use crate::plan::Plan;
+use crate::raw_photo;
+use crate::transfer::{self, LinkMode};
+use crate::util::collision_free_path;
+
+pub fn h2_missing_h1(h1: HashSet<Photo>, h2: HashSet<Photo>) -> HashSet<Photo> {
+    h1.into_iter().filter(|photo1| !h2.contains(photo1)).collect()
+}
+
+/// Copies `src` into the second database through a verified temporary file, keeping its times and
+/// permissions. Copying follows symlinks, so every link type in the first database becomes an
+/// independent copy.
+fn copy_file_with_directory_creation(
+    src: &Path, dst: &Path, hash: i64, xattrs: bool,
+) -> io::Result<PathBuf> {
+    if let Some(parent) = dst.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    transfer::link_or_copy(src, dst, LinkMode::Copy, Some(hash), xattrs)?;
+    Ok(dst.to_path_buf())
+}
+
+fn summary(missing: usize, synced: usize) {
+    events::summary("sync", vec![("missing", missing), ("synced", synced)]);
+}
+
+/// Copies the photos of the library at `db1` that are missing from the library at `db2` into it,
+/// laid out by the second library's config. Only prints the copies unless `apply` is set, or
+/// writes them to `plan_path` for `photodb apply`.
+pub fn sync_libraries(
+    db1: &Path, pool1: &Pool<ConnectionManager<SqliteConnection>>, db2: &Path, apply: bool,
+    plan_path: Option<&PathBuf>, xattrs: bool,
+) {
+    events::note(format!("Syncing {} and {}", db1.display(), db2.display()));
+    let config2 = LibraryConfig::load(db2)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let template = config2.layout.path_template();
+    let pool2 = db::get_connection_pool_for(&config2.database_path(db2))
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let photos1 =
+        get_photos(pool1).unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    let photos2 =
+        get_photos(&pool2).unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+    events::note(format!("Found {} photos in {}", photos1.len(), db1.display()));
+    events::note(format!("Found {} photos in {}", photos2.len(), db2.display()));
+    events::note("Finding missing photos...");
+    let mut missing1: Vec<Photo> = h2_missing_h1(photos1, photos2).into_iter().collect();
+    missing1.sort_by(|a, b| (a.exif_date, &a.current_path).cmp(&(b.exif_date, &b.current_path)));
+    events::note(format!(
+        "Found {} missing photos in {} from {}.",
+        missing1.len(),
+        db2.display(),
+        db1.display()
+    ));
+    //print the list of missing photos:
+    let mut taken = HashSet::new();
+    let insert_list: Vec<(PathBuf, Photo)> = missing1
+        .iter()
+        .filter_map(|photo| {
+            //test if the file exists in the second database:
+            let rendered = template.render_unused(db2, photo, None, &taken);
+            let filename = collision_free_path(&rendered, photo.hash, |p| {
+                !taken.contains(p)
+                    && (!p.exists()
+                        || raw_photo::Photo::hash_file(&p.to_path_buf()).ok().map(|h| h as i64)
+                            == Some(photo.hash))
+            });
+            taken.insert(filename.clone());
+            if filename.exists() {
+                events::note(format!("\t{} exists in second database.", filename.display()));
+                None
+            } else {
+                let new_photo = photo.placed_at(&filename, LinkMode::Copy);
+                Some((PathBuf::from(&photo.current_path), new_photo))
+            }
+        })
+        .collect();
+
+    if let Some(plan_path) = plan_path {
+        let mut plan = Plan::new("sync", db2, xattrs);
+        for (source, new_photo) in insert_list.iter() {
+            plan.place(source, new_photo.clone())
+                .map_err(|e| events::emit(Event::failed(Category::Read, &e)))
+                .ok();
+        }
+        plan.write(plan_path).unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)));
+        events::note(format!(
+            "Wrote {} operations to {}, run `photodb apply {}` to run them",
+            plan.operations.len(),
+            plan_path.display(),
+            plan_path.display()
+        ));
+        summary(missing1.len(), 0);
+    } else if apply {
+        let _temp_lock = transfer::lock_temp_files(db2);
+        let moved: Vec<()> = insert_list
+            .par_iter()
+            .filter_map(|(source, new_photo)| {
+                let dest = PathBuf::from(&new_photo.current_path);
+                copy_file_with_directory_creation(source, &dest, new_photo.hash, xattrs)
+                    .map_err(|e| {
+                        let message = format!("copying to {}: {}", dest.display(), e);
+                        events::emit(Event::error(Category::Copy, source, message));
+                    })
+                    .ok()?;
+                events::emit(Event::copied(source, &dest, LinkMode::Copy, false));
+                // The row is only committed once the file is in place.
+                db::insert_file_to_db(new_photo, &pool2)
+                    .map_err(|e| {
+                        events::emit(Event::error(
+                            Category::Insert,
+                            &dest,
+                            format!("inserting file: {}", e.details()),
+                        ));
+                        fs::remove_file(&dest).ok();
+                    })
+                    .ok()
+                    .map(|_| events::emit(Event::inserted(source, &dest, new_photo.hash, false)))
+            })
+            .collect();
+        events::note(format!("Synced {}/{} photos.", moved.len(), missing1.len()));
+        summary(missing1.len(), moved.len());
+    } else {
+        for (source, new_photo) in insert_list.iter() {
+            let dest = Path::new(&new_photo.current_path);
+            events::emit(Event::copied(source, dest, LinkMode::Copy, true));
+            events::emit(Event::inserted(source, dest, new_photo.hash, true));
+        }
+        events::note(format!("Would have synced {}/{} photos.", insert_list.len(), missing1.len()));
+        summary(missing1.len(), 0);
+    }
+}
@@ -0,0 +1,125 @@
+#![allow(non_upper_case_globals)]
+
+use core::slice;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, RgbImage};
+use libraw_rs_vendor::{
+    libraw_close, libraw_data_t, libraw_dcraw_clear_mem, libraw_dcraw_make_mem_image,
+    libraw_dcraw_make_mem_thumb, libraw_dcraw_process, libraw_init, libraw_open_buffer,
+    libraw_processed_image_t, libraw_unpack, libraw_unpack_thumb, LibRaw_errors_LIBRAW_SUCCESS,
+    LibRaw_image_formats_LIBRAW_IMAGE_BITMAP, LibRaw_image_formats_LIBRAW_IMAGE_JPEG,
+};
+use std::path::{Path, PathBuf};
+
+use crate::photodb_error::PhotoDBError;
+use crate::util::write_to_path;
+
+const THUMB_SIZE: u32 = 512;
+const THUMB_QUALITY: u8 = 85;
+
+/// Location of the cached thumbnail for a photo hash, sharded by the first byte of the hash.
+pub fn build_thumb_path(db_root: &Path, hash: i64) -> PathBuf {
+    let name = format!("{:016x}", hash as u64);
+    db_root.join(".photodb").join("thumbs").join(&name[..2]).join(format!("{}.jpg", name))
+}
+
+/// Writes a thumbnail for `buf` into the cache unless one already exists for `hash`.
+pub fn write_thumbnail(
+    buf: &[u8], og_path: &Path, db_root: &Path, hash: i64,
+) -> Result<PathBuf, PhotoDBError> {
+    let thumb_path = build_thumb_path(db_root, hash);
+    if thumb_path.exists() {
+        return Ok(thumb_path);
+    }
+    let preview = extract_preview(buf, og_path)?;
+    let thumb = preview.thumbnail(THUMB_SIZE, THUMB_SIZE).to_rgb8();
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, THUMB_QUALITY)
+        .encode_image(&thumb)
+        .map_err(|e| PhotoDBError::new(format!("encoding thumbnail: {}", e).as_str(), og_path))?;
+    write_to_path(&jpeg, &thumb_path)
+        .map_err(|e| PhotoDBError::new(format!("writing thumbnail: {}", e).as_str(), og_path))?;
+    Ok(thumb_path)
+}
+
+/// Returns the embedded preview of a raw, or a half-size render when it has none.
+pub fn extract_preview(buf: &[u8], og_path: &Path) -> Result<DynamicImage, PhotoDBError> {
+    read_embedded_preview(buf, og_path).or_else(|_| render_half_size(buf, og_path))
+}
+
+fn read_embedded_preview(buf: &[u8], og_path: &Path) -> Result<DynamicImage, PhotoDBError> {
+    let libraw_data = unsafe { libraw_init(0) };
+    let preview = open_buffer(libraw_data, buf, og_path).and_then(|_| {
+        match unsafe { libraw_unpack_thumb(libraw_data) } {
+            LibRaw_errors_LIBRAW_SUCCESS => {
+                let mut errc = 0;
+                let thumb = unsafe { libraw_dcraw_make_mem_thumb(libraw_data, &mut errc) };
+                decode_processed_image(thumb, errc, og_path)
+            }
+            _ => Err(PhotoDBError::new("libraw_unpack_thumb failed", og_path)),
+        }
+    });
+    unsafe { libraw_close(libraw_data) };
+    preview
+}
+
+fn render_half_size(buf: &[u8], og_path: &Path) -> Result<DynamicImage, PhotoDBError> {
+    let libraw_data = unsafe { libraw_init(0) };
+    unsafe { (*libraw_data).params.half_size = 1 };
+    let preview = open_buffer(libraw_data, buf, og_path).and_then(|_| {
+        match unsafe { libraw_unpack(libraw_data) } {
+            LibRaw_errors_LIBRAW_SUCCESS => match unsafe { libraw_dcraw_process(libraw_data) } {
+                LibRaw_errors_LIBRAW_SUCCESS => {
+                    let mut errc = 0;
+                    let render = unsafe { libraw_dcraw_make_mem_image(libraw_data, &mut errc) };
+                    decode_processed_image(render, errc, og_path)
+                }
+                _ => Err(PhotoDBError::new("libraw_dcraw_process failed", og_path)),
+            },
+            _ => Err(PhotoDBError::new("libraw_unpack failed", og_path)),
+        }
+    });
+    unsafe { libraw_close(libraw_data) };
+    preview
+}
+
+fn open_buffer(
+    libraw_data: *mut libraw_data_t, buf: &[u8], og_path: &Path,
+) -> Result<(), PhotoDBError> {
+    match unsafe { libraw_open_buffer(libraw_data, buf.as_ptr() as *const _, buf.len()) } {
+        LibRaw_errors_LIBRAW_SUCCESS => Ok(()),
+        _ => Err(PhotoDBError::new("libraw_open_buffer failed", og_path)),
+    }
+}
+
+/// Copies a libraw in-memory image into a `DynamicImage` and frees the libraw allocation.
+fn decode_processed_image(
+    processed: *mut libraw_processed_image_t, errc: i32, og_path: &Path,
+) -> Result<DynamicImage, PhotoDBError> {
+    if processed.is_null() || errc != LibRaw_errors_LIBRAW_SUCCESS {
+        return Err(PhotoDBError::new("libraw could not build a preview image", og_path));
+    }
+    let (format, width, height, colors, bits, data) = unsafe {
+        let data =
+            slice::from_raw_parts((*processed).data.as_ptr(), (*processed).data_size as usize);
+        (
+            (*processed).type_,
+            (*processed).width as u32,
+            (*processed).height as u32,
+            (*processed).colors,
+            (*processed).bits,
+            data.to_vec(),
+        )
+    };
+    unsafe { libraw_dcraw_clear_mem(processed) };
+    match format {
+        LibRaw_image_formats_LIBRAW_IMAGE_JPEG => image::load_from_memory(&data)
+            .map_err(|e| PhotoDBError::new(format!("decoding preview: {}", e).as_str(), og_path)),
+        LibRaw_image_formats_LIBRAW_IMAGE_BITMAP if colors == 3 && bits == 8 => {
+            RgbImage::from_raw(width, height, data)
+                .map(DynamicImage::ImageRgb8)
+                .ok_or_else(|| PhotoDBError::new("preview bitmap has the wrong size", og_path))
+        }
+        _ => Err(PhotoDBError::new("unsupported preview format", og_path)),
+    }
+}
@@ -0,0 +1,244 @@
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use regex::Regex;
+use rexiv2::Metadata;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::companions::move_with_companions;
+use crate::dates::{find_sidecar, parse_date, CaptureTime};
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::models::Photo;
+use crate::path_template::PathTemplate;
+use crate::photodb_error::PhotoDBError;
+use crate::util::collision_free_path;
+
+const EMPTY_XMP: &str = concat!(
+    "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+    "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+    " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+    " </rdf:RDF>\n",
+    "</x:xmpmeta>\n",
+    "<?xpacket end=\"w\"?>\n"
+);
+
+/// A photo whose capture time is being corrected, and where it will live afterwards.
+pub struct Shift {
+    pub photo: Photo,
+    pub new_time: CaptureTime,
+    pub new_path: PathBuf,
+}
+
+/// Parses a clock correction such as `+5h30m`, `-1d` or `90s`.
+pub fn parse_shift(shift: &str) -> Result<Duration, String> {
+    let pattern = Regex::new(r"^([+-])?(?:(\d+)d)?(?:(\d+)h)?(?:(\d+)m)?(?:(\d+)s)?$").unwrap();
+    let captures =
+        pattern
+            .captures(shift.trim())
+            .filter(|c| (2..=5).any(|i| c.get(i).is_some()))
+            .ok_or_else(|| format!("invalid time shift '{}', expected e.g. +5h30m", shift))?;
+    let number = |i: usize| captures.get(i).map_or(0, |m| m.as_str().parse::<i64>().unwrap_or(0));
+    let duration = Duration::days(number(2))
+        + Duration::hours(number(3))
+        + Duration::minutes(number(4))
+        + Duration::seconds(number(5));
+    match captures.get(1).map(|m| m.as_str()) {
+        Some("-") => Ok(-duration),
+        _ => Ok(duration),
+    }
+}
+
+/// Parses a wall clock time given on the command line, e.g. `2023-01-01 12:00:00`.
+pub fn parse_local_time(time: &str) -> Result<NaiveDateTime, String> {
+    parse_date(time).map(|t| t.local).ok_or_else(|| format!("invalid date '{}'", time))
+}
+
+/// Computes the corrected time and library location of every photo, moving each by `delta`.
+pub fn plan_shift(
+    photos: Vec<Photo>, delta: Duration, db_root: &Path, template: &PathTemplate,
+) -> Vec<Shift> {
+    let mut taken = HashSet::new();
+    photos
+        .into_iter()
+        .map(|photo| {
+            let time = photo.capture_time();
+            let new_time = CaptureTime::new(time.local + delta, time.offset);
+            let mut shifted = photo.clone();
+            shifted.exif_date = new_time.timestamp();
+            let current = Path::new(&photo.current_path);
+            let new_path = template.render_unused(db_root, &shifted, Some(current), &taken);
+            let new_path = collision_free_path(&new_path, photo.hash, |p| {
+                p == current || (!p.exists() && !taken.contains(p))
+            });
+            taken.insert(new_path.clone());
+            Shift { photo, new_time, new_path }
+        })
+        .collect()
+}
+
+/// Updates the database and moves the files (with their sidecars and paired JPEGs) in one
+/// transaction. If any step fails, the database is rolled back and the files already moved are
+/// moved back.
+pub fn apply_shift(
+    shifts: &[Shift], pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> Result<usize, PhotoDBError> {
+    use crate::schema::photos::dsl::*;
+    let mut conn = pool.get()?;
+    let mut moved: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let result = conn.transaction::<_, PhotoDBError, _>(|conn| {
+        for shift in shifts.iter() {
+            let current = PathBuf::from(&shift.photo.current_path);
+            diesel::update(photos.filter(hash.eq(shift.photo.hash)))
+                .set((
+                    exif_date.eq(shift.new_time.timestamp()),
+                    current_path.eq(shift.new_path.to_string_lossy().to_string()),
+                ))
+                .execute(conn)?;
+            if current != shift.new_path {
+                move_with_companions(&current, &shift.new_path, &mut moved)?;
+            }
+        }
+        Ok(shifts.len())
+    });
+    if result.is_err() {
+        for (from, to) in moved.iter().rev() {
+            fs::rename(to, from)
+                .map_err(|e| {
+                    events::emit(Event::error(Category::Move, to, format!("moving back: {}", e)))
+                })
+                .ok();
+        }
+    }
+    result
+}
+
+/// Writes the corrected capture time into the photo's XMP sidecar, creating one if needed.
+pub fn write_xmp_date(path: &Path, time: &CaptureTime) -> Result<PathBuf, PhotoDBError> {
+    let sidecar = find_sidecar(path).unwrap_or_else(|| path.with_extension("xmp"));
+    if !sidecar.exists() {
+        fs::write(&sidecar, EMPTY_XMP).map_err(|e| {
+            PhotoDBError::new(format!("creating sidecar: {}", e).as_str(), &sidecar)
+        })?;
+    }
+    let date = match time.offset {
+        Some(offset) => time.local.and_local_timezone(offset).unwrap().to_rfc3339(),
+        None => time.local.format("%Y-%m-%dT%H:%M:%S").to_string(),
+    };
+    Metadata::new_from_path(&sidecar)
+        .and_then(|xmp| {
+            xmp.set_tag_string("Xmp.exif.DateTimeOriginal", &date)?;
+            xmp.set_tag_string("Xmp.photoshop.DateCreated", &date)?;
+            xmp.save_to_file(&sidecar)
+        })
+        .map_err(|e| PhotoDBError::new(format!("writing sidecar: {}", e).as_str(), &sidecar))?;
+    Ok(sidecar)
+}
+
+/// Moves the capture time of the photos taken with one camera, either `by` a fixed amount or so
+/// that `reference` was taken at `true_time`. Without a camera or a time range, every photo is
+/// only shifted when `all` is set. Only prints the changes unless `apply` is set.
+#[allow(clippy::too_many_arguments)]
+pub fn shift_photos(
+    db_root: &Path, model: Option<String>, serial: Option<String>,
+    between: Option<Vec<NaiveDateTime>>, all: bool, by: Option<Duration>,
+    reference: Option<PathBuf>, true_time: Option<NaiveDateTime>, write_xmp: bool, apply: bool,
+    database: &Pool<ConnectionManager<SqliteConnection>>, template: &PathTemplate,
+) {
+    let (delta, model, serial) = match (by, reference, true_time) {
+        (Some(by), _, _) => (by, model, serial),
+        (None, Some(reference), Some(true_time)) => {
+            let photo = db::get_photo_by_path(&reference, database)
+                .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)))
+                .unwrap_or_else(|| {
+                    events::fatal(Event::error(Category::Input, &reference, "not in the database"));
+                });
+            let delta = true_time - photo.capture_time().local;
+            (delta, model.or(photo.camera_model), serial.or(photo.serial))
+        }
+        _ => unreachable!("clap requires --by or --reference with --true-time"),
+    };
+    if !all && model.is_none() && serial.is_none() && between.is_none() {
+        let message = "no camera or time range to shift, pass --all to shift every photo";
+        events::fatal(Event::error(Category::Input, db_root, message));
+    }
+    let photos: Vec<_> = db::get_photos_by_camera(model.as_deref(), serial.as_deref(), database)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)))
+        .into_iter()
+        .filter(|photo| match &between {
+            Some(range) => {
+                let local = photo.capture_time().local;
+                range[0] <= local && local <= range[1]
+            }
+            None => true,
+        })
+        .collect();
+    events::note(format!("Shifting {} photos by {}", photos.len(), delta));
+    let shifts = plan_shift(photos, delta, db_root, template);
+    for shift in shifts.iter() {
+        events::emit(Event::Shifted {
+            from: shift.photo.current_path.clone(),
+            to: events::path(&shift.new_path),
+            old_time: shift.photo.capture_time().local.to_string(),
+            new_time: shift.new_time.local.to_string(),
+            dry_run: !apply,
+        });
+    }
+    if !apply {
+        return;
+    }
+    match apply_shift(&shifts, database) {
+        Ok(shifted) => {
+            events::note(format!("Shifted {}/{} photos", shifted, shifts.len()));
+            events::summary("timeshift", vec![("planned", shifts.len()), ("shifted", shifted)]);
+        }
+        Err(e) => {
+            events::note("No photos were shifted");
+            events::fatal(Event::failed(Category::Move, &e));
+        }
+    }
+    if write_xmp {
+        for shift in shifts.iter() {
+            match write_xmp_date(&shift.new_path, &shift.new_time) {
+                Ok(sidecar) => events::note(format!("wrote sidecar: {}", sidecar.display())),
+                Err(e) => events::emit(Event::failed(Category::Metadata, &e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_shift("90s"), Ok(Duration::seconds(90)));
+        assert_eq!(parse_shift("15m"), Ok(Duration::minutes(15)));
+        assert_eq!(parse_shift("2h"), Ok(Duration::hours(2)));
+        assert_eq!(parse_shift("1d"), Ok(Duration::days(1)));
+        assert_eq!(
+            parse_shift("1d2h3m4s"),
+            Ok(Duration::days(1)
+                + Duration::hours(2)
+                + Duration::minutes(3)
+                + Duration::seconds(4))
+        );
+    }
+
+    #[test]
+    fn parses_signs() {
+        assert_eq!(parse_shift("+5h30m"), Ok(Duration::hours(5) + Duration::minutes(30)));
+        assert_eq!(parse_shift("-1d"), Ok(-Duration::days(1)));
+        assert_eq!(parse_shift(" -45m "), Ok(-Duration::minutes(45)));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for shift in ["", "+", "-", "5", "5x", "1h1d", "h", "+-1h", "1.5h", "1 h", "--1h"] {
+            assert!(parse_shift(shift).is_err(), "accepted '{}'", shift);
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use clap::ValueEnum;
+use filetime::FileTime;
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use crate::events::{self, Category, Event};
+use crate::photodb_error::PhotoDBError;
+use crate::raw_photo::Photo;
+
+const TEMP_SUFFIX: &str = ".photodb-tmp";
+
+static IO_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static COPYING: Mutex<usize> = Mutex::new(0);
+static COPY_DONE: Condvar = Condvar::new();
+
+/// How a library file is created from its source, recorded in the `link_type` column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LinkMode {
+    /// Copy the file
+    Copy,
+    /// Hard link the file, which must be on the same filesystem
+    Hard,
+    /// Clone the file copy-on-write (btrfs, XFS), falling back to a copy
+    Reflink,
+    /// Symlink to the source, which must stay where it is
+    Symlink,
+}
+
+impl LinkMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkMode::Copy => "copy",
+            LinkMode::Hard => "hard",
+            LinkMode::Reflink => "reflink",
+            LinkMode::Symlink => "symlink",
+        }
+    }
+
+    /// Reads a `link_type` column. Unknown values are treated as copies.
+    pub fn from_db(link_type: &str) -> Self {
+        match link_type {
+            "hard" => LinkMode::Hard,
+            "reflink" => LinkMode::Reflink,
+            "symlink" => LinkMode::Symlink,
+            _ => LinkMode::Copy,
+        }
+    }
+}
+
+/// Creates `to` from `from` with `mode`, never replacing an existing file. Copies and reflinks
+/// are written to a temporary file next to `to` and checked against `hash` (the pixel hash of a
+/// raw, as stored in the database) or, without one, against the bytes of `from` before they are
+/// moved into place. They keep the permissions and times of `from`, and its extended attributes
+/// with `xattrs`. Returns the mode that was used, which is `Copy` when a reflink was not possible.
+pub fn link_or_copy(
+    from: &Path, to: &Path, mode: LinkMode, hash: Option<i64>, xattrs: bool,
+) -> Result<LinkMode, io::Error> {
+    match mode {
+        LinkMode::Copy => copy_atomic(from, to, hash, false, xattrs),
+        LinkMode::Hard => fs::hard_link(from, to).map(|_| LinkMode::Hard),
+        LinkMode::Reflink => copy_atomic(from, to, hash, true, xattrs),
+        LinkMode::Symlink => symlink(&fs::canonicalize(from)?, to).map(|_| LinkMode::Symlink),
+    }
+}
+
+/// Name of the temporary file `to` is written to. It is hidden, so it is never imported.
+pub fn build_temp_path(to: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(to.file_name().unwrap_or_default());
+    name.push(TEMP_SUFFIX);
+    to.with_file_name(name)
+}
+
+/// Limits how many copies run at once, across all threads. 0 leaves it to the thread count.
+pub fn set_io_limit(limit: usize) {
+    IO_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// A turn to copy a file, held until it is dropped.
+struct CopySlot;
+
+impl CopySlot {
+    /// Waits until fewer copies than the IO limit are running.
+    fn acquire() -> CopySlot {
+        let limit = IO_LIMIT.load(Ordering::Relaxed);
+        let mut copying = COPYING.lock().unwrap();
+        while limit > 0 && *copying >= limit {
+            copying = COPY_DONE.wait(copying).unwrap();
+        }
+        *copying += 1;
+        CopySlot
+    }
+}
+
+impl Drop for CopySlot {
+    fn drop(&mut self) {
+        *COPYING.lock().unwrap() -= 1;
+        COPY_DONE.notify_one();
+    }
+}
+
+fn copy_atomic(
+    from: &Path, to: &Path, hash: Option<i64>, reflink: bool, xattrs: bool,
+) -> Result<LinkMode, io::Error> {
+    if to.exists() {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+    let _slot = CopySlot::acquire();
+    let temp = build_temp_path(to);
+    let result = write_temp(from, &temp, reflink)
+        .and_then(|used| verify_copy(from, &temp, hash).map(|_| used))
+        // Verifying reads the copy, so its times are set afterwards.
+        .and_then(|used| preserve_metadata(from, &temp, xattrs).map(|_| used))
+        .and_then(|used| rename_new(&temp, to).map(|_| used));
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+/// Writes `buf`, the bytes of a raw read from an archive, to `to` the way `link_or_copy` copies
+/// a file: through a temporary file that is checked against `hash` and never replacing `to`.
+pub fn write_new_verified(buf: &[u8], to: &Path, hash: i64) -> Result<(), io::Error> {
+    if to.exists() {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+    let _slot = CopySlot::acquire();
+    let temp = build_temp_path(to);
+    let result = write_new(buf, &temp)
+        .and_then(|_| match Photo::hash_file(&temp.to_path_buf()).ok().map(|h| h as i64) {
+            Some(copied) if copied == hash => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("copy of {} does not match the archive entry", to.display()),
+            )),
+        })
+        .and_then(|_| rename_new(&temp, to));
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+fn write_new(buf: &[u8], to: &Path) -> Result<(), io::Error> {
+    let mut dest = OpenOptions::new().write(true).create_new(true).open(to)?;
+    dest.write_all(buf)?;
+    dest.sync_all()
+}
+
+fn write_temp(from: &Path, temp: &Path, reflink: bool) -> Result<LinkMode, io::Error> {
+    if reflink && reflink_copy::reflink(from, temp).is_ok() {
+        File::open(temp)?.sync_all()?;
+        return Ok(LinkMode::Reflink);
+    }
+    copy_new(from, temp).map(|_| LinkMode::Copy)
+}
+
+fn verify_copy(from: &Path, temp: &Path, hash: Option<i64>) -> Result<(), io::Error> {
+    let matches = match hash {
+        Some(hash) => Photo::hash_file(&temp.to_path_buf()).ok().map(|h| h as i64) == Some(hash),
+        None => fs::read(from)? == fs::read(temp)?,
+    };
+    match matches {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("copy of {} does not match the source", from.display()),
+        )),
+    }
+}
+
+/// Moves `temp` to `to` without replacing a file created there in the meantime, then syncs the
+/// directory so the new name survives a crash.
+fn rename_new(temp: &Path, to: &Path) -> Result<(), io::Error> {
+    match fs::hard_link(temp, to) {
+        Ok(_) => fs::remove_file(temp)?,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+        // Filesystems without hard links, such as exFAT.
+        Err(_) if to.exists() => return Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+        Err(_) => fs::rename(temp, to)?,
+    }
+    sync_dir(to.parent().unwrap())
+}
+
+/// Gives `to` the permissions, access and modification times and, with `xattrs`, the extended
+/// attributes of `from`.
+pub fn preserve_metadata(from: &Path, to: &Path, xattrs: bool) -> Result<(), io::Error> {
+    let meta = fs::metadata(from)?;
+    fs::set_permissions(to, meta.permissions())?;
+    if xattrs {
+        copy_xattrs(from, to)?;
+    }
+    filetime::set_file_times(
+        to,
+        FileTime::from_last_access_time(&meta),
+        FileTime::from_last_modification_time(&meta),
+    )?;
+    File::open(to)?.sync_all()
+}
+
+/// Attributes the destination filesystem rejects are reported and skipped.
+#[cfg(unix)]
+fn copy_xattrs(from: &Path, to: &Path) -> Result<(), io::Error> {
+    for name in xattr::list(from)? {
+        if let Some(value) = xattr::get(from, &name)? {
+            xattr::set(to, &name, &value)
+                .map_err(|e| {
+                    let message = format!("copying attribute {}: {}", name.to_string_lossy(), e);
+                    events::emit(Event::error(Category::Metadata, to, message))
+                })
+                .ok();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_xattrs(_from: &Path, _to: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Differences in permissions, modification time and extended attributes between a source and
+/// its library copy.
+pub fn metadata_drift(from: &Path, to: &Path) -> Result<Vec<String>, io::Error> {
+    let (source, copy) = (fs::metadata(from)?, fs::metadata(to)?);
+    let mut drift = Vec::new();
+    let (source_mtime, copy_mtime) = (
+        FileTime::from_last_modification_time(&source),
+        FileTime::from_last_modification_time(&copy),
+    );
+    if source_mtime != copy_mtime {
+        drift.push(format!("mtime {} != {}", source_mtime, copy_mtime));
+    }
+    if source.permissions() != copy.permissions() {
+        drift.push(format!("permissions {:?} != {:?}", source.permissions(), copy.permissions()));
+    }
+    drift.extend(xattr_drift(from, to)?);
+    Ok(drift)
+}
+
+#[cfg(unix)]
+fn xattr_drift(from: &Path, to: &Path) -> Result<Vec<String>, io::Error> {
+    let names: BTreeSet<_> = xattr::list(from)?.chain(xattr::list(to)?).collect();
+    let mut drift = Vec::new();
+    for name in names {
+        if xattr::get(from, &name)? != xattr::get(to, &name)? {
+            drift.push(format!("xattr {}", name.to_string_lossy()));
+        }
+    }
+    Ok(drift)
+}
+
+#[cfg(not(unix))]
+fn xattr_drift(_from: &Path, _to: &Path) -> Result<Vec<String>, io::Error> {
+    Ok(Vec::new())
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(windows)]
+fn sync_dir(_dir: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Locks the temporary files of `root` for a run that is about to copy into it, first removing
+/// the ones interrupted runs left behind. Leftovers are only removed when no other run holds the
+/// lock, so the temporary files of a concurrent import, sync or apply are never touched. The lock
+/// is held until the returned file is dropped.
+pub fn lock_temp_files(root: &Path) -> Option<File> {
+    let lock_path = root.join(".photodb").join("temp.lock");
+    let locked = fs::create_dir_all(root.join(".photodb"))
+        .and_then(|_| OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path))
+        .and_then(|file| {
+            match file.try_lock() {
+                Ok(()) => {
+                    clean_temp_files(root);
+                    file.unlock()?;
+                }
+                Err(TryLockError::WouldBlock) => events::note(format!(
+                    "Another run is copying into {}, keeping its temporary files",
+                    root.display()
+                )),
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+            file.lock_shared().map(|_| file)
+        });
+    locked
+        .map_err(|e| {
+            let message = format!("locking temporary files: {}", e);
+            events::emit(Event::error(Category::Copy, &lock_path, message))
+        })
+        .ok()
+}
+
+/// Removes the temporary files an interrupted import or sync left under `root`.
+fn clean_temp_files(root: &Path) -> usize {
+    let pattern = root.join("**").join(format!("*{}", TEMP_SUFFIX));
+    glob::glob(pattern.to_str().expect("join"))
+        .map(|paths| {
+            paths
+                .filter_map(|path| path.ok())
+                .filter(|path| {
+                    fs::remove_file(path)
+                        .map(|_| {
+                            events::emit(Event::Deleted {
+                                path: events::path(path),
+                                dry_run: false,
+                            })
+                        })
+                        .is_ok()
+                })
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn symlink(from: &Path, to: &Path) -> Result<(), io::Error> {
+    std::os::unix::fs::symlink(from, to)
+}
+
+#[cfg(windows)]
+fn symlink(from: &Path, to: &Path) -> Result<(), io::Error> {
+    std::os::windows::fs::symlink_file(from, to)
+}
+
+/// Copies `from` to `to` and syncs the copy to disk. Fails with `AlreadyExists` instead of
+/// overwriting an existing file.
+pub fn copy_new(from: &Path, to: &Path) -> Result<u64, io::Error> {
+    let mut source = File::open(from)?;
+    let mut dest = OpenOptions::new().write(true).create_new(true).open(to)?;
+    let copied = io::copy(&mut source, &mut dest)?;
+    dest.sync_all()?;
+    Ok(copied)
+}
+
+/// Fails when deleting `source` would lose the photo: when the library copy `dest` is a symlink,
+/// which hashes as the source it points to but would be left dangling, or is `source` itself.
+/// Hard links keep the data when the source is deleted.
+pub fn check_independent_copy(source: &PathBuf, dest: &PathBuf) -> Result<(), PhotoDBError> {
+    let metadata = fs::symlink_metadata(dest)
+        .map_err(|e| PhotoDBError::new(format!("reading library copy: {}", e).as_str(), source))?;
+    if metadata.file_type().is_symlink() {
+        let message = format!("library copy {} is a symlink, keeping source", dest.display());
+        return Err(PhotoDBError::new(message.as_str(), source));
+    }
+    match (fs::canonicalize(source), fs::canonicalize(dest)) {
+        (Ok(source_path), Ok(dest_path)) if source_path == dest_path => {
+            Err(PhotoDBError::new("library copy is the source itself, keeping source", source))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Deletes the raw `source` once `dest` is confirmed to be an independent copy holding the same
+/// pixels, hashed the same way as on import and compared as stored in the database.
+pub fn delete_verified_source(
+    source: &PathBuf, dest: &PathBuf, hash: i64,
+) -> Result<(), PhotoDBError> {
+    check_independent_copy(source, dest)?;
+    let dest_hash = Photo::hash_file(dest)? as i64;
+    if dest_hash != hash {
+        return Err(PhotoDBError::new(
+            format!("library copy {} has a different hash", dest.display()).as_str(),
+            source,
+        ));
+    }
+    fs::remove_file(source)
+        .map_err(|e| PhotoDBError::new(format!("deleting source: {}", e).as_str(), source))
+}
+
+/// Deletes a sidecar or paired image once `dest` is confirmed to be a byte for byte copy.
+pub fn delete_verified_companion(source: &PathBuf, dest: &PathBuf) -> Result<(), PhotoDBError> {
+    let read = |path: &PathBuf| {
+        fs::read(path).map_err(|e| PhotoDBError::new(format!("reading file: {}", e).as_str(), path))
+    };
+    if read(source)? != read(dest)? {
+        return Err(PhotoDBError::new(
+            format!("library copy {} differs", dest.display()).as_str(),
+            source,
+        ));
+    }
+    fs::remove_file(source)
+        .map_err(|e| PhotoDBError::new(format!("deleting source: {}", e).as_str(), source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_temp_files_while_another_run_copies() {
+        let root = std::env::temp_dir().join(format!("photodb-temp-lock-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("2024")).unwrap();
+        let copying = lock_temp_files(&root).unwrap();
+        let temp = build_temp_path(&root.join("2024").join("DSC0001.ARW"));
+        fs::write(&temp, b"raw").unwrap();
+
+        drop(lock_temp_files(&root).unwrap());
+        assert!(temp.exists());
+
+        drop(copying);
+        drop(lock_temp_files(&root).unwrap());
+        assert!(!temp.exists());
+        fs::remove_dir_all(&root).ok();
+    }
+}
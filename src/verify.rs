@@ -0,0 +1,172 @@
+use clap::ValueEnum;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use glob::{glob_with, MatchOptions};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::events::{self, Category, Event};
+use crate::models;
+use crate::raw_photo::Photo;
+use crate::transfer::{self, LinkMode};
+
+/// What `verify` checks.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum VerifyMode {
+    /// Run hashes and check the database
+    Hash,
+    /// Check for untracked files in the database root
+    File,
+    /// Compare the times, permissions and extended attributes of each copy with its source
+    Metadata,
+}
+
+/// Re-hashes every library file, returning how each one turned out: `verified`, `mismatch`,
+/// `missing` or `error`.
+fn verify_photo(photo: &models::Photo) -> &'static str {
+    let path = PathBuf::from(&photo.current_path);
+    let link = LinkMode::from_db(&photo.link_type);
+    if link == LinkMode::Symlink {
+        match fs::read_link(&path) {
+            Ok(target) if !path.exists() => {
+                let message = format!("dangling symlink to {}", target.display());
+                events::emit(Event::error(Category::Read, &path, message));
+                return "error";
+            }
+            Ok(_) => {}
+            Err(_) if path.exists() => {
+                events::emit(Event::error(Category::Read, &path, "expected a symlink"));
+            }
+            Err(_) => {}
+        }
+    }
+    if !path.exists() {
+        events::emit(Event::Missing { path: events::path(&path) });
+        return "missing";
+    }
+    let hash = match Photo::hash_file(&path) {
+        Ok(hash) => hash as i64,
+        Err(e) => {
+            events::emit(Event::error(Category::Decode, &path, format!("calculating hash: {}", e)));
+            return "error";
+        }
+    };
+    if hash != photo.hash {
+        events::emit(Event::Mismatch {
+            path: events::path(&path),
+            expected: photo.hash,
+            actual: hash,
+        });
+        if link == LinkMode::Hard {
+            events::note(format!(
+                "\thard linked to {}, which may have been edited",
+                photo.original_path
+            ));
+        }
+        return "mismatch";
+    }
+    events::emit(Event::Verified { path: events::path(&path), hash });
+    "verified"
+}
+
+fn verify_hashes(database: &Pool<ConnectionManager<SqliteConnection>>) {
+    let photos: Vec<models::Photo> = db::get_photos(database)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)))
+        .into_iter()
+        .collect();
+    let outcomes: Vec<&str> = photos.par_iter().map(verify_photo).collect();
+    events::note(format!("Done verifying {} photos", photos.len()));
+    let count = |outcome: &str| outcomes.iter().filter(|o| **o == outcome).count();
+    events::summary(
+        "verify",
+        vec![
+            ("photos", photos.len()),
+            ("verified", count("verified")),
+            ("mismatch", count("mismatch")),
+            ("missing", count("missing")),
+            ("errors", count("error")),
+        ],
+    );
+}
+
+fn verify_metadata(database: &Pool<ConnectionManager<SqliteConnection>>) {
+    let photos: Vec<models::Photo> = db::get_photos(database)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)))
+        .into_iter()
+        .collect();
+    let drifted = photos
+        .par_iter()
+        .filter(|photo| {
+            let source = PathBuf::from(&photo.original_path);
+            let copy = PathBuf::from(&photo.current_path);
+            // Links share their metadata with the source, and moved sources cannot be compared.
+            let link = LinkMode::from_db(&photo.link_type);
+            if link == LinkMode::Hard || link == LinkMode::Symlink || !source.exists() {
+                return false;
+            }
+            match transfer::metadata_drift(&source, &copy) {
+                Ok(drift) if drift.is_empty() => false,
+                Ok(drift) => {
+                    events::emit(Event::MetadataDrift {
+                        path: events::path(&copy),
+                        differences: drift,
+                    });
+                    true
+                }
+                Err(e) => {
+                    events::emit(Event::error(
+                        Category::Read,
+                        &copy,
+                        format!("reading metadata: {}", e),
+                    ));
+                    true
+                }
+            }
+        })
+        .count();
+    events::note(format!("Found {} photos whose metadata differs from their source", drifted));
+    events::summary("verify-metadata", vec![("photos", photos.len()), ("drifted", drifted)]);
+}
+
+fn verify_files(db_root: &Path, database: &Pool<ConnectionManager<SqliteConnection>>) {
+    let tracked: HashSet<PathBuf> = db::get_photos(database)
+        .unwrap_or_else(|e| events::fatal(Event::failed(Category::Input, &e)))
+        .into_iter()
+        .map(|photo| PathBuf::from(photo.current_path))
+        .collect();
+    // Sidecars and paired JPEGs are stored next to their photo under the same stem.
+    let stems: HashSet<PathBuf> = tracked.iter().map(|path| path.with_extension("")).collect();
+    let options: MatchOptions = Default::default();
+    let files: Vec<PathBuf> =
+        glob_with(db_root.join("**/*").as_os_str().to_str().expect("join"), options)
+            .unwrap()
+            .filter_map(|x| x.ok())
+            .filter(|path| !path.starts_with(db_root.join(".photodb")) && !path.is_dir())
+            .collect();
+
+    let untracked = files
+        .par_iter()
+        .filter(|file| {
+            !tracked.contains(*file)
+                && !stems.contains(&file.with_extension(""))
+                && !tracked.contains(&file.with_extension(""))
+        })
+        .map(|file| events::emit(Event::Untracked { path: events::path(file) }))
+        .count();
+    events::note(format!("Found {} untracked files in {}", untracked, db_root.display()));
+    events::summary("verify-files", vec![("files", files.len()), ("untracked", untracked)]);
+}
+
+/// Checks the library at `db_root` against its database.
+pub fn verify(
+    mode: VerifyMode, db_root: &Path, database: &Pool<ConnectionManager<SqliteConnection>>,
+) {
+    match mode {
+        VerifyMode::Hash => verify_hashes(database),
+        VerifyMode::File => verify_files(db_root, database),
+        VerifyMode::Metadata => verify_metadata(database),
+    }
+}
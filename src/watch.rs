@@ -0,0 +1,123 @@
+use notify::event::{AccessKind, AccessMode, EventKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::events::{self, Category, Event};
+use crate::photodb_error::PhotoDBError;
+
+/// How often pending files are checked and the stop flag is read.
+const TICK: Duration = Duration::from_millis(500);
+
+/// How long files have to stay unchanged before they are imported, and how long to wait for
+/// more files before importing the ones that are ready.
+pub struct WatchOptions {
+    pub settle: Duration,
+    pub batch: Duration,
+}
+
+/// A file that was created or written to, waiting for its size to stop changing.
+struct Pending {
+    size: u64,
+    changed: Instant,
+}
+
+/// Watches `dir` and everything below it until `stop` is set, handing files to `import` in
+/// batches once each has kept the same size for `options.settle`. `initial` runs once the watch
+/// is registered, so files added while it runs are picked up too. A batch that fails is reported
+/// and watching goes on. Files still being written when `stop` is set are left for the next run.
+pub fn watch_folder(
+    dir: &Path, options: &WatchOptions, stop: &AtomicBool, initial: impl FnOnce(),
+    mut import: impl FnMut(Vec<PathBuf>) -> Result<(), PhotoDBError>,
+) -> Result<(), PhotoDBError> {
+    let watch_error =
+        |e: notify::Error| PhotoDBError::new(format!("watching: {}", e).as_str(), dir);
+    let (sender, receiver) = channel();
+    let mut watcher =
+        RecommendedWatcher::new(sender, notify::Config::default()).map_err(watch_error)?;
+    watcher.watch(dir, RecursiveMode::Recursive).map_err(watch_error)?;
+    initial();
+    let mut import = |batch: Vec<PathBuf>| {
+        if let Err(e) = import(batch) {
+            events::emit(Event::failed(Category::Input, &e));
+        }
+    };
+
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+    let mut ready: Vec<PathBuf> = Vec::new();
+    let mut first_ready: Option<Instant> = None;
+    while !stop.load(Ordering::Relaxed) {
+        match receiver.recv_timeout(TICK) {
+            Ok(Ok(event)) if is_write(&event.kind) => {
+                for path in event.paths {
+                    for file in files_under(&path) {
+                        let size = fs::metadata(&file).map_or(0, |m| m.len());
+                        pending.insert(file, Pending { size, changed: Instant::now() });
+                    }
+                }
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            // Errors like a dropped event queue are reported and watching goes on.
+            Ok(Err(e)) => {
+                events::emit(Event::error(Category::Read, dir, format!("watching: {}", e)))
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let now = Instant::now();
+        pending.retain(|path, file| match fs::metadata(path) {
+            Ok(metadata) if metadata.len() != file.size => {
+                file.size = metadata.len();
+                file.changed = now;
+                true
+            }
+            Ok(_) if now.duration_since(file.changed) >= options.settle => {
+                ready.push(path.clone());
+                first_ready.get_or_insert(now);
+                false
+            }
+            Ok(_) => true,
+            // Removed or renamed before it settled.
+            Err(_) => false,
+        });
+        let waited = first_ready.is_some_and(|t| now.duration_since(t) >= options.batch);
+        if !ready.is_empty() && (pending.is_empty() || waited) {
+            ready.sort();
+            ready.dedup();
+            import(std::mem::take(&mut ready));
+            first_ready = None;
+        }
+    }
+    if !ready.is_empty() {
+        import(ready);
+    }
+    Ok(())
+}
+
+/// Whether an event may have created or finished writing a file.
+fn is_write(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    )
+}
+
+/// `path` itself if it is a file, or the files below it if it is a directory that was created or
+/// moved in, since those may have been written before the directory was watched.
+fn files_under(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    if !path.is_dir() {
+        return Vec::new();
+    }
+    glob::glob(path.join("**/*").to_str().expect("join"))
+        .map(|paths| paths.filter_map(|p| p.ok()).filter(|p| p.is_file()).collect())
+        .unwrap_or_default()
+}
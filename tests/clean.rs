@@ -0,0 +1,100 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use photodb::{clean, db, models::Photo, transfer::LinkMode};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("photodb-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn row(original: &Path, current: &Path, link: LinkMode) -> Photo {
+    Photo {
+        hash: 42,
+        original_path: original.to_string_lossy().to_string(),
+        current_path: current.to_string_lossy().to_string(),
+        exif_json: "{}".to_string(),
+        exif_date: 0,
+        camera_make: None,
+        camera_model: None,
+        lens: None,
+        serial: None,
+        iso: None,
+        shutter: None,
+        aperture: None,
+        focal_length: None,
+        metadata_sources: "{}".to_string(),
+        date_source: None,
+        utc_offset: None,
+        subsec_ms: None,
+        link_type: link.as_str().to_string(),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn clean_keeps_sources_of_symlinked_photos() {
+    let dir = scratch_dir("clean-symlink");
+    let (card, db_root) = (dir.join("card"), dir.join("library"));
+    fs::create_dir_all(&card).unwrap();
+    fs::create_dir_all(db_root.join(".photodb")).unwrap();
+    let source = card.join("DSC0001.ARW");
+    fs::write(&source, b"raw").unwrap();
+    let linked = db_root.join("DSC0001.ARW");
+    std::os::unix::fs::symlink(&source, &linked).unwrap();
+    let pool = db::get_connection_pool_for(&db::build_config_path(&db_root)).unwrap();
+    db::insert_file_to_db(&row(&source, &linked, LinkMode::Symlink), &pool).unwrap();
+
+    clean::clean_sources(&db_root, &card, true, None, &pool);
+
+    assert!(source.exists());
+    assert_eq!(fs::read(&linked).unwrap(), b"raw");
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[cfg(unix)]
+#[test]
+fn clean_plans_skip_symlinked_photos() {
+    let dir = scratch_dir("clean-symlink-plan");
+    let (card, db_root) = (dir.join("card"), dir.join("library"));
+    fs::create_dir_all(&card).unwrap();
+    fs::create_dir_all(db_root.join(".photodb")).unwrap();
+    let source = card.join("DSC0002.ARW");
+    fs::write(&source, b"raw").unwrap();
+    let linked = db_root.join("DSC0002.ARW");
+    std::os::unix::fs::symlink(&source, &linked).unwrap();
+    let pool = db::get_connection_pool_for(&db::build_config_path(&db_root)).unwrap();
+    db::insert_file_to_db(&row(&source, &linked, LinkMode::Symlink), &pool).unwrap();
+
+    let plan_path = dir.join("clean.json");
+    clean::clean_sources(&db_root, &card, false, Some(&plan_path), &pool);
+
+    assert!(photodb::plan::Plan::read(&plan_path).unwrap().operations.is_empty());
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn original_prefix_matches_only_the_directory() {
+    let dir = scratch_dir("original-prefix");
+    let db_root = dir.join("library");
+    fs::create_dir_all(db_root.join(".photodb")).unwrap();
+    let pool = db::get_connection_pool_for(&db::build_config_path(&db_root)).unwrap();
+    let card = dir.join("card_1");
+    for (hash, original) in [
+        (1, card.join("a.ARW")),
+        (2, dir.join("card_12").join("b.ARW")),
+        (3, dir.join("cardx1").join("c.ARW")),
+    ] {
+        let mut photo = row(&original, &db_root.join(format!("{}.ARW", hash)), LinkMode::Copy);
+        photo.hash = hash;
+        db::insert_file_to_db(&photo, &pool).unwrap();
+    }
+
+    let matches = db::get_photos_by_original_prefix(&card, &pool).unwrap();
+
+    let hashes: Vec<i64> = matches.iter().map(|photo| photo.hash).collect();
+    assert_eq!(hashes, vec![1]);
+    fs::remove_dir_all(&dir).ok();
+}